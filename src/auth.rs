@@ -0,0 +1,200 @@
+use crate::base64;
+use crate::config::Config;
+use crate::url;
+use crate::{HTTPMethod, HTTPRequest, HTTPResponse, HTTPStatusCode};
+
+/// Whether the request may proceed under `--auth-credentials`: always when
+/// none are configured or the path is outside `--auth-prefix`, otherwise
+/// only with a matching `Authorization: Basic` header. CORS preflights
+/// never carry credentials, so they are let through when `--cors-origin`
+/// is set; any other OPTIONS request needs credentials like the rest.
+pub fn authorized(request: &HTTPRequest, config: &Config) -> bool {
+    let Some(credentials) = &config.auth_credentials else {
+        return true;
+    };
+    if !is_protected(&request.path, &config.auth_prefix) || is_preflight(request, config) {
+        return true;
+    }
+    request
+        .headers
+        .get("authorization")
+        .and_then(|authorization| {
+            let (scheme, token) = authorization.split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("basic") {
+                return None;
+            }
+            base64::decode(token.trim())
+        })
+        .is_some_and(|given| constant_time_eq(&given, credentials.as_bytes()))
+}
+
+fn is_preflight(request: &HTTPRequest, config: &Config) -> bool {
+    config.cors_origin.is_some()
+        && request.method == HTTPMethod::OPTIONS
+        && request.headers.contains_key("origin")
+        && request
+            .headers
+            .contains_key("access-control-request-method")
+}
+
+/// Whether `path` is `prefix` or below it. Both are normalised first, as
+/// the files endpoints would resolve them, so `/a/%62` and `/a//b` can't
+/// slip past a prefix of `/a/b`, and the match is on whole segments, so
+/// `/admin` doesn't cover `/administrator`.
+fn is_protected(path: &str, prefix: &str) -> bool {
+    let path = url::normalize_path(path);
+    let prefix = url::normalize_path(prefix);
+    prefix == "/"
+        || path == prefix
+        || path
+            .strip_prefix(&prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The 401 asking the client to authenticate for `--auth-realm`.
+pub fn challenge(config: &Config) -> HTTPResponse {
+    HTTPResponse::new(HTTPStatusCode::Unauthorized).with_header(
+        "WWW-Authenticate",
+        format!("Basic realm=\"{}\"", config.auth_realm.replace('"', "\\\"")),
+    )
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// doesn't reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    use crate::tests::{config_for, exchange, response, scratch_dir};
+
+    /// `alice:secret`, guarding `/files/private`.
+    fn protected(dir: &Path) -> Config {
+        fs::create_dir_all(dir.join("private")).unwrap();
+        fs::write(dir.join("private/s.txt"), "secret stuff").unwrap();
+        fs::write(dir.join("public.txt"), "open").unwrap();
+        Config {
+            auth_credentials: Some("alice:secret".to_string()),
+            auth_prefix: "/files/private".to_string(),
+            ..config_for(dir)
+        }
+    }
+
+    async fn get(config: &Config, path: &str, authorization: Option<&str>) -> u16 {
+        let authorization = authorization
+            .map(|credentials| {
+                format!(
+                    "Authorization: Basic {}\r\n",
+                    base64::encode(credentials.as_bytes())
+                )
+            })
+            .unwrap_or_default();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: test\r\n{}Connection: close\r\n\r\n",
+            path, authorization
+        );
+        response(&exchange(config, request.as_bytes()).await).status
+    }
+
+    #[tokio::test]
+    async fn missing_credentials_are_challenged() {
+        let config = protected(&scratch_dir("auth-missing"));
+        let output = exchange(
+            &config,
+            b"GET /files/private/s.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let response = response(&output);
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            response.header("WWW-Authenticate"),
+            Some("Basic realm=\"Restricted\"")
+        );
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_challenged() {
+        let config = protected(&scratch_dir("auth-wrong"));
+        let status = get(&config, "/files/private/s.txt", Some("alice:guess")).await;
+        assert_eq!(status, 401);
+    }
+
+    #[tokio::test]
+    async fn correct_credentials_are_let_through() {
+        let config = protected(&scratch_dir("auth-correct"));
+        let output = exchange(
+            &config,
+            b"GET /files/private/s.txt HTTP/1.1\r\nHost: test\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+        )
+        .await;
+        let response = response(&output);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "secret stuff");
+    }
+
+    #[tokio::test]
+    async fn paths_outside_the_prefix_need_no_credentials() {
+        let config = protected(&scratch_dir("auth-outside"));
+        assert_eq!(get(&config, "/files/public.txt", None).await, 200);
+    }
+
+    #[tokio::test]
+    async fn disguised_paths_under_the_prefix_are_still_protected() {
+        let config = protected(&scratch_dir("auth-disguised"));
+        for path in [
+            "/files/%70rivate/s.txt",
+            "/files//private/s.txt",
+            "/files/./private/s.txt",
+            "/files/x/../private/s.txt",
+            "/files/private%2Fs.txt",
+        ] {
+            assert_eq!(get(&config, path, None).await, 401, "{}", path);
+        }
+    }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        assert!(is_protected("/admin", "/admin"));
+        assert!(is_protected("/admin/users", "/admin"));
+        assert!(is_protected("/admin/users", "/admin/"));
+        assert!(!is_protected("/administrator", "/admin"));
+        assert!(is_protected("/anything", "/"));
+    }
+
+    #[tokio::test]
+    async fn only_cors_preflights_skip_credentials() {
+        let options = |headers: &str| {
+            format!(
+                "OPTIONS /files/private/s.txt HTTP/1.1\r\nHost: test\r\n{}\r\n",
+                headers
+            )
+        };
+        let preflight =
+            options("Origin: https://app.example\r\nAccess-Control-Request-Method: PUT\r\n");
+        let config = protected(&scratch_dir("auth-options"));
+        let plain = exchange(&config, options("").as_bytes()).await;
+        assert_eq!(response(&plain).status, 401);
+        // Without `--cors-origin` there are no preflights to answer.
+        let refused = exchange(&config, preflight.as_bytes()).await;
+        assert_eq!(response(&refused).status, 401);
+
+        let config = Config {
+            cors_origin: Some("https://app.example".to_string()),
+            ..config
+        };
+        let plain = exchange(&config, options("").as_bytes()).await;
+        assert_eq!(response(&plain).status, 401);
+        let allowed = exchange(&config, preflight.as_bytes()).await;
+        assert_eq!(response(&allowed).status, 204);
+    }
+}
@@ -0,0 +1,77 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes as standard, padded base64 (RFC 4648).
+pub fn encode(input: &[u8]) -> String {
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+    for group in input.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..group.len()].copy_from_slice(group);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3F;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard, padded base64 (RFC 4648), returning `None` on any
+/// character outside the alphabet or a length that isn't a multiple of four.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, quad) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = quad.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &byte in &quad[..4 - padding] {
+            let value = ALPHABET.iter().position(|&symbol| symbol == byte)?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+        let bytes = bits.to_be_bytes();
+        decoded.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vectors from RFC 4648, section 10.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_and_decodes_the_rfc_vectors() {
+        for (plain, encoded) in VECTORS {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in ["Zg", "Zg=", "Z===", "Zg==Zm8=", "Zm9v!A==", "Zm 9v"] {
+            assert_eq!(decode(input), None, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+/// Returns true when an `Accept-Encoding` header value lists gzip with a
+/// non-zero quality, e.g. `gzip, deflate` or `br;q=1.0, gzip;q=0.8`.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        if !name.eq_ignore_ascii_case("gzip") {
+            return false;
+        }
+        params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .all(|quality| quality.trim().parse::<f32>().map_or(true, |q| q > 0.0))
+    })
+}
+
+/// Whether gzip is worth applying to a body of this type: formats that are
+/// compressed already, such as most images and archives, only grow.
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    let media_type = media_type.to_ascii_lowercase();
+    if media_type == "image/svg+xml" {
+        return true;
+    }
+    !(media_type.starts_with("image/")
+        || media_type.starts_with("audio/")
+        || media_type.starts_with("video/")
+        || matches!(
+            media_type.as_str(),
+            "application/zip" | "application/gzip" | "application/x-gzip"
+        ))
+}
+
+pub fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Gzips a body as it is read, for ones too large to compress in memory
+/// first. A read error from `inner` is passed on rather than ending the
+/// compressed stream early.
+pub struct GzipReader<R> {
+    inner: R,
+    /// `None` once the gzip trailer has been produced.
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    /// Compressed bytes not yet handed out, from `offset` on.
+    output: Vec<u8>,
+    offset: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> GzipReader<R> {
+    pub fn new(inner: R) -> GzipReader<R> {
+        GzipReader {
+            inner,
+            encoder: Some(GzEncoder::new(Vec::new(), Compression::default())),
+            output: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for GzipReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.offset < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.offset);
+                buf.put_slice(&this.output[this.offset..this.offset + len]);
+                this.offset += len;
+                return Poll::Ready(Ok(()));
+            }
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+            let available = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            let output = if available.is_empty() {
+                this.encoder
+                    .take()
+                    .map_or(Ok(Vec::new()), GzEncoder::finish)?
+            } else {
+                let len = available.len();
+                encoder.write_all(available)?;
+                Pin::new(&mut this.inner).consume(len);
+                mem::take(encoder.get_mut())
+            };
+            // The encoder may hold input back, leaving nothing to hand out
+            // until more is read.
+            this.output = output;
+            this.offset = 0;
+        }
+    }
+}
+
+/// Undoes one request `Content-Encoding`, or returns `None` for a coding
+/// we don't support. At most `limit + 1` bytes are produced, so a caller
+/// can tell an oversized body from one that just fits without inflating
+/// all of it.
+pub fn decode(coding: &str, body: &[u8], limit: usize) -> Option<io::Result<Vec<u8>>> {
+    let decoder: Box<dyn Read + '_> = match coding.trim().to_ascii_lowercase().as_str() {
+        "identity" => Box::new(body),
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(body)),
+        // HTTP's "deflate" is the zlib format, not raw deflate.
+        "deflate" => Box::new(ZlibDecoder::new(body)),
+        _ => return None,
+    };
+    let mut decoded = Vec::new();
+    Some(
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map(|_| decoded),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_is_accepted_only_when_listed_with_a_nonzero_quality() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("gzip, deflate"));
+        assert!(accepts_gzip("br;q=1.0, GZIP;q=0.8"));
+        assert!(!accepts_gzip("identity"));
+        assert!(!accepts_gzip("deflate, br"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip(""));
+    }
+
+    #[test]
+    fn gzipped_bodies_decode_to_the_original() {
+        let body = b"hello hello hello hello".repeat(10);
+        let compressed = gzip(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        let decoded = decode("gzip", &compressed, body.len()).unwrap().unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn decoding_stops_just_past_the_limit() {
+        let compressed = gzip(&[0; 10_000]).unwrap();
+        let decoded = decode("gzip", &compressed, 100).unwrap().unwrap();
+        assert_eq!(decoded.len(), 101);
+        assert!(decode("br", &compressed, 100).is_none());
+    }
+
+    #[test]
+    fn compressed_formats_are_not_compressed_again() {
+        for content_type in [
+            "text/html; charset=utf-8",
+            "application/json",
+            "image/svg+xml",
+        ] {
+            assert!(is_compressible(content_type), "{}", content_type);
+        }
+        for content_type in ["image/png", "IMAGE/JPEG", "video/mp4", "application/zip"] {
+            assert!(!is_compressible(content_type), "{}", content_type);
+        }
+    }
+}
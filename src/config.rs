@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::log::{self, Level};
+use crate::proxy::Upstream;
+
+/// Server settings collected from the command line.
+pub struct Config {
+    pub directory: String,
+    /// Named roots from `--directory name=path`, served under
+    /// `/files/<name>/`. When any are given they replace `directory` for
+    /// the files endpoints.
+    pub roots: HashMap<String, PathBuf>,
+    /// Directories from `--vhost host=path`, served in place of
+    /// `directory` to requests whose Host names them.
+    pub vhosts: HashMap<String, PathBuf>,
+    pub host: IpAddr,
+    pub port: u16,
+    /// Listens on this Unix domain socket instead of `host` and `port`.
+    pub unix_socket: Option<PathBuf>,
+    pub max_body_size: usize,
+    /// The most of a request body read off the connection at a time, so
+    /// its buffer grows with what arrives rather than what was declared.
+    pub read_buffer_size: usize,
+    /// Bytes allowed for the request line and headers together.
+    pub max_header_size: usize,
+    /// Header lines allowed in one request.
+    pub max_headers: usize,
+    /// The Content-Type of files with an unrecognised extension.
+    pub default_content_type: String,
+    /// Bodies shorter than this are never gzip-compressed.
+    pub gzip_min_length: u64,
+    /// How long each of the request line, headers and body may take to
+    /// arrive.
+    pub read_timeout: Duration,
+    /// How long a persistent connection may sit idle between requests.
+    pub keepalive_timeout: Duration,
+    /// Requests served on one connection before it is closed.
+    pub max_requests_per_conn: usize,
+    /// Connections served at once; further ones wait to be accepted.
+    /// Unlimited when unset.
+    pub max_connections: Option<usize>,
+    /// Connections the kernel queues awaiting accept.
+    pub backlog: u32,
+    /// Requests per second allowed from each client IP; unlimited when
+    /// unset.
+    pub rate_limit: Option<f64>,
+    /// Suppresses the per-request access log.
+    pub quiet: bool,
+    /// The most verbose diagnostics printed; request summaries are info.
+    pub log_level: Level,
+    /// The layout of the access log.
+    pub log_format: log::Format,
+    /// Answers everything but `/health` and `/metrics` with 503, to take
+    /// the server out of rotation.
+    pub maintenance: bool,
+    /// Serves a single connection, then exits.
+    pub once: bool,
+    /// Serves symlinks that point outside the served directory, which are
+    /// otherwise refused.
+    pub follow_symlinks: bool,
+    /// Served for every GET, whatever its path, in place of the routes.
+    pub serve_file: Option<PathBuf>,
+    /// Served in place of a 404 for GETs of pages missing from the served
+    /// directory, so a single-page app can handle its own routes.
+    pub spa_fallback: Option<PathBuf>,
+    /// Files tried, in order, when a directory is requested.
+    pub index_names: Vec<String>,
+    /// Redirects directory URLs without a trailing slash to the slashed
+    /// one; off with `--no-redirect`.
+    pub redirect_directories: bool,
+    /// Lists directories that have no index file instead of answering 404.
+    pub autoindex: bool,
+    /// Gives error responses a small JSON body describing the status.
+    pub json_errors: bool,
+    /// Holds `404.html` and the like, served as the body of error
+    /// responses.
+    pub error_pages: Option<PathBuf>,
+    /// PEM certificate chain and private key; HTTPS is served when both
+    /// are set.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Sent as Access-Control-Allow-Origin; no CORS headers when unset.
+    pub cors_origin: Option<String>,
+    /// Forwards every request to this upstream instead of the handlers.
+    pub proxy_pass: Option<Upstream>,
+    /// Extra `--header` headers added to every response.
+    pub headers: Vec<(String, String)>,
+    /// Sent as the Server header; `--no-server-header` leaves it out.
+    pub server_token: Option<String>,
+    /// `user:pass` required, via Basic auth, for paths under `auth_prefix`.
+    pub auth_credentials: Option<String>,
+    pub auth_realm: String,
+    pub auth_prefix: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            directory: ".".to_string(),
+            roots: HashMap::new(),
+            vhosts: HashMap::new(),
+            host: IpAddr::from([127, 0, 0, 1]),
+            port: 4221,
+            unix_socket: None,
+            max_body_size: 10 * 1024 * 1024,
+            read_buffer_size: 64 * 1024,
+            max_header_size: 8 * 1024,
+            max_headers: 100,
+            default_content_type: "application/octet-stream".to_string(),
+            gzip_min_length: 1024,
+            read_timeout: Duration::from_secs(30),
+            keepalive_timeout: Duration::from_secs(5),
+            max_requests_per_conn: 100,
+            max_connections: None,
+            backlog: 1024,
+            rate_limit: None,
+            quiet: false,
+            log_level: Level::Info,
+            log_format: log::Format::Common,
+            maintenance: false,
+            once: false,
+            follow_symlinks: false,
+            serve_file: None,
+            spa_fallback: None,
+            index_names: vec!["index.html".to_string()],
+            redirect_directories: true,
+            autoindex: false,
+            json_errors: false,
+            error_pages: None,
+            tls_cert: None,
+            tls_key: None,
+            cors_origin: None,
+            proxy_pass: None,
+            headers: Vec::new(),
+            server_token: Some(concat!("http-server-rust/", env!("CARGO_PKG_VERSION")).to_string()),
+            auth_credentials: None,
+            auth_realm: "Restricted".to_string(),
+            auth_prefix: "/".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, String> {
+        Config::parse(env::args().skip(1))
+    }
+
+    /// Builds a config from command-line arguments, program name excluded.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} expects a value", flag));
+            match flag.as_str() {
+                "--directory" => {
+                    let directory = value()?;
+                    match directory.split_once('=') {
+                        Some((name, path)) if !name.is_empty() && !name.contains('/') => {
+                            config.roots.insert(name.to_string(), PathBuf::from(path));
+                        }
+                        _ => config.directory = directory,
+                    }
+                }
+                "--vhost" => {
+                    let vhost = value()?;
+                    match vhost.split_once('=') {
+                        Some((host, path)) if !host.is_empty() && !path.is_empty() => {
+                            config
+                                .vhosts
+                                .insert(host.to_ascii_lowercase(), PathBuf::from(path));
+                        }
+                        _ => return Err(format!("invalid value for {}: {}", flag, vhost)),
+                    }
+                }
+                "--host" => config.host = parse_value(&flag, value()?)?,
+                "--port" => config.port = parse_value(&flag, value()?)?,
+                "--max-body-size" => config.max_body_size = parse_value(&flag, value()?)?,
+                "--read-buffer-size" => {
+                    config.read_buffer_size = parse_value(&flag, value()?)?;
+                    if config.read_buffer_size == 0 {
+                        return Err(format!("invalid value for {}: 0", flag));
+                    }
+                }
+                "--max-headers" => config.max_headers = parse_value(&flag, value()?)?,
+                "--max-header-size" => config.max_header_size = parse_value(&flag, value()?)?,
+                "--default-content-type" => config.default_content_type = value()?,
+                "--gzip-min-length" => config.gzip_min_length = parse_value(&flag, value()?)?,
+                "--read-timeout" => {
+                    config.read_timeout = Duration::from_secs(parse_value(&flag, value()?)?)
+                }
+                "--keepalive-timeout" => {
+                    config.keepalive_timeout = Duration::from_secs(parse_value(&flag, value()?)?)
+                }
+                "--max-requests-per-conn" => {
+                    config.max_requests_per_conn = parse_value(&flag, value()?)?;
+                    if config.max_requests_per_conn == 0 {
+                        return Err(format!("invalid value for {}: 0", flag));
+                    }
+                }
+                "--max-connections" => {
+                    let max_connections = parse_value(&flag, value()?)?;
+                    // No permit would ever be granted, so nothing served.
+                    if max_connections == 0 {
+                        return Err(format!("invalid value for {}: 0", flag));
+                    }
+                    config.max_connections = Some(max_connections);
+                }
+                "--unix-socket" => config.unix_socket = Some(value()?.into()),
+                "--backlog" => config.backlog = parse_value(&flag, value()?)?,
+                "--rate-limit" => {
+                    let rate: f64 = parse_value(&flag, value()?)?;
+                    if !(rate > 0.0 && rate.is_finite()) {
+                        return Err(format!("invalid value for {}: {}", flag, rate));
+                    }
+                    config.rate_limit = Some(rate)
+                }
+                "--proxy-pass" => config.proxy_pass = Some(parse_value(&flag, value()?)?),
+                "--cors-origin" => config.cors_origin = Some(value()?),
+                "--header" => {
+                    let header = value()?;
+                    match header.split_once(':') {
+                        Some((name, value)) if !name.trim().is_empty() => config
+                            .headers
+                            .push((name.trim().to_string(), value.trim().to_string())),
+                        _ => return Err(format!("invalid value for {}: {}", flag, header)),
+                    }
+                }
+                "--auth-credentials" => {
+                    let credentials = value()?;
+                    if !credentials.contains(':') {
+                        return Err(format!("invalid value for {}: expected user:pass", flag));
+                    }
+                    config.auth_credentials = Some(credentials)
+                }
+                "--auth-realm" => config.auth_realm = value()?,
+                "--auth-prefix" => config.auth_prefix = value()?,
+                "--server-token" => config.server_token = Some(value()?),
+                "--no-server-header" => config.server_token = None,
+                "--quiet" => config.quiet = true,
+                "--log-format" => config.log_format = parse_value(&flag, value()?)?,
+                "--log-level" => config.log_level = parse_value(&flag, value()?)?,
+                "--maintenance" => config.maintenance = true,
+                "--once" => config.once = true,
+                "--follow-symlinks" => config.follow_symlinks = true,
+                "--serve-file" => config.serve_file = Some(value()?.into()),
+                "--spa-fallback" => config.spa_fallback = Some(value()?.into()),
+                "--index-names" => {
+                    let names = value()?;
+                    config.index_names = names
+                        .split(',')
+                        .map(|name| name.trim().to_string())
+                        .collect();
+                    if config
+                        .index_names
+                        .iter()
+                        .any(|name| name.is_empty() || name.contains('/'))
+                    {
+                        return Err(format!("invalid value for {}: {}", flag, names));
+                    }
+                }
+                "--no-redirect" => config.redirect_directories = false,
+                "--autoindex" => config.autoindex = true,
+                "--json-errors" => config.json_errors = true,
+                "--error-pages" => config.error_pages = Some(value()?.into()),
+                "--tls-cert" => config.tls_cert = Some(value()?.into()),
+                "--tls-key" => config.tls_key = Some(value()?.into()),
+                _ => return Err(format!("unknown argument: {}", flag)),
+            }
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key must be given together".to_string());
+        }
+        Ok(config)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn listens_on_localhost_4221_by_default() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.address(), "127.0.0.1:4221".parse().unwrap());
+    }
+
+    #[test]
+    fn host_and_port_flags_set_the_address() {
+        let config = parse(&["--host", "0.0.0.0", "--port", "8080"]).unwrap();
+        assert_eq!(config.address(), "0.0.0.0:8080".parse().unwrap());
+        let config = parse(&["--host", "::1"]).unwrap();
+        assert_eq!(config.address(), "[::1]:4221".parse().unwrap());
+    }
+
+    #[test]
+    fn bad_addresses_are_reported_by_flag() {
+        assert_eq!(
+            parse(&["--port", "70000"]).err().unwrap(),
+            "invalid value for --port: 70000"
+        );
+        assert_eq!(
+            parse(&["--host", "localhost"]).err().unwrap(),
+            "invalid value for --host: localhost"
+        );
+        assert_eq!(parse(&["--port"]).err().unwrap(), "--port expects a value");
+    }
+
+    #[test]
+    fn named_directories_become_roots() {
+        let config = parse(&["--directory", "docs=/srv/docs", "--directory", "/srv/www"]).unwrap();
+        assert_eq!(config.directory, "/srv/www");
+        assert_eq!(config.roots.len(), 1);
+        assert_eq!(config.roots["docs"], PathBuf::from("/srv/docs"));
+    }
+
+    #[test]
+    fn header_flags_accumulate() {
+        let config = parse(&["--header", "X-One: 1", "--header", "X-Two:two words"]).unwrap();
+        assert_eq!(
+            config.headers,
+            [
+                ("X-One".to_string(), "1".to_string()),
+                ("X-Two".to_string(), "two words".to_string())
+            ]
+        );
+        assert!(parse(&["--header", "no colon"]).is_err());
+    }
+
+    #[test]
+    fn the_server_token_flags_override_and_suppress() {
+        let config = parse(&["--server-token", "edge/2"]).unwrap();
+        assert_eq!(config.server_token.as_deref(), Some("edge/2"));
+        let config = parse(&["--server-token", "edge/2", "--no-server-header"]).unwrap();
+        assert_eq!(config.server_token, None);
+    }
+
+    #[test]
+    fn the_log_level_flag_is_checked() {
+        assert_eq!(parse(&[]).unwrap().log_level, Level::Info);
+        let config = parse(&["--log-level", "debug"]).unwrap();
+        assert_eq!(config.log_level, Level::Debug);
+        assert!(parse(&["--log-level", "loud"]).is_err());
+    }
+
+    #[test]
+    fn the_log_format_flag_picks_common_or_combined() {
+        assert_eq!(parse(&[]).unwrap().log_format, log::Format::Common);
+        let config = parse(&["--log-format", "combined"]).unwrap();
+        assert_eq!(config.log_format, log::Format::Combined);
+        assert!(parse(&["--log-format", "json"]).is_err());
+    }
+
+    #[test]
+    fn index_names_are_a_comma_separated_list() {
+        assert_eq!(parse(&[]).unwrap().index_names, ["index.html"]);
+        let config = parse(&["--index-names", "index.html, index.htm,default.html"]).unwrap();
+        assert_eq!(
+            config.index_names,
+            ["index.html", "index.htm", "default.html"]
+        );
+        assert!(parse(&["--index-names", "index.html,,x"]).is_err());
+        assert!(parse(&["--index-names", "sub/index.html"]).is_err());
+    }
+
+    #[test]
+    fn vhosts_are_keyed_by_lowercased_host() {
+        let config = parse(&["--vhost", "Blog.Test=/srv/blog"]).unwrap();
+        assert_eq!(config.vhosts["blog.test"], PathBuf::from("/srv/blog"));
+        assert!(parse(&["--vhost", "blog.test"]).is_err());
+        assert!(parse(&["--vhost", "=/srv/blog"]).is_err());
+    }
+
+    #[test]
+    fn max_connections_must_allow_at_least_one() {
+        assert_eq!(parse(&[]).unwrap().max_connections, None);
+        let config = parse(&["--max-connections", "8"]).unwrap();
+        assert_eq!(config.max_connections, Some(8));
+        let error = parse(&["--max-connections", "0"]).err().unwrap();
+        assert_eq!(error, "invalid value for --max-connections: 0");
+    }
+
+    #[test]
+    fn connections_must_allow_at_least_one_request() {
+        let config = parse(&["--max-requests-per-conn", "1"]).unwrap();
+        assert_eq!(config.max_requests_per_conn, 1);
+        let error = parse(&["--max-requests-per-conn", "0"]).err().unwrap();
+        assert_eq!(error, "invalid value for --max-requests-per-conn: 0");
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+/// Parses a `Cookie` header such as `a=1; b=2` into name/value pairs.
+/// Values may themselves contain `=`; pairs without one are skipped, and
+/// later duplicates override earlier ones.
+pub fn parse(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            (!name.is_empty()).then(|| (name.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pairs_and_keeps_equals_signs_in_values() {
+        let cookies = parse("a=1; b=2; c=x=y");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["a"], "1");
+        assert_eq!(cookies["b"], "2");
+        assert_eq!(cookies["c"], "x=y");
+    }
+
+    #[test]
+    fn trims_whitespace_and_skips_pairs_without_a_value() {
+        let cookies = parse("  a = 1 ;flag;; =orphan; b=2 ");
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["a"], "1");
+        assert_eq!(cookies["b"], "2");
+    }
+
+    #[test]
+    fn later_duplicates_win() {
+        assert_eq!(parse("a=1; a=2")["a"], "2");
+    }
+}
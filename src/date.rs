@@ -0,0 +1,200 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A UTC calendar time broken into its fields.
+pub struct DateTime {
+    pub year: i64,
+    /// 1-based.
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        // Times before the epoch don't occur for anything we format.
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let of_day = seconds.rem_euclid(86400) as u32;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: of_day / 3600,
+            minute: of_day / 60 % 60,
+            second: of_day % 60,
+        }
+    }
+
+    pub fn now() -> DateTime {
+        DateTime::from_system_time(SystemTime::now())
+    }
+
+    /// Formats as an RFC 7231 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+    pub fn to_http_date(&self) -> String {
+        let days = days_from_civil(self.year, self.month, self.day);
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Formats as in the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`.
+    pub fn to_log_format(&self) -> String {
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+/// Parses an RFC 7231 IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+/// The obsolete RFC 850 and asctime forms are not accepted.
+pub fn parse_http_date(input: &str) -> Option<SystemTime> {
+    let (weekday, rest) = input.trim().split_once(", ")?;
+    if !WEEKDAYS.contains(&weekday) {
+        return None;
+    }
+    let mut parts = rest.split(' ');
+    let day = parts.next()?;
+    let month = parts.next()?;
+    let year = parts.next()?;
+    let time = parts.next()?;
+    if parts.next() != Some("GMT") || parts.next().is_some() || day.len() != 2 || year.len() != 4 {
+        return None;
+    }
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|field| {
+        if field.len() == 2 {
+            field.parse::<u64>().ok()
+        } else {
+            None
+        }
+    });
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60
+    {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Converts a (year, month, day) date into days since 1970-01-01, using
+/// Howard Hinnant's `days_from_civil` algorithm.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date, using
+/// Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example date from RFC 7231, section 7.1.1.1.
+    const RFC_EXAMPLE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn formats_imf_fixdates() {
+        assert_eq!(
+            DateTime::from_system_time(at(784111777)).to_http_date(),
+            RFC_EXAMPLE
+        );
+        assert_eq!(
+            DateTime::from_system_time(at(0)).to_http_date(),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        assert_eq!(
+            DateTime::from_system_time(at(951782400)).to_http_date(),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn formats_log_timestamps() {
+        assert_eq!(
+            DateTime::from_system_time(at(971186136)).to_log_format(),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        assert_eq!(parse_http_date(RFC_EXAMPLE), Some(at(784111777)));
+        for seconds in [0, 951782400, 1700000000, 4102444799] {
+            let formatted = DateTime::from_system_time(at(seconds)).to_http_date();
+            assert_eq!(
+                parse_http_date(&formatted),
+                Some(at(seconds)),
+                "{}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_date_forms() {
+        for input in [
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Xyz 1994 08:49:37 GMT",
+            "yesterday",
+            "",
+        ] {
+            assert_eq!(parse_http_date(input), None, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,2102 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+
+use crate::compression;
+use crate::config::Config;
+use crate::date::{self, DateTime};
+use crate::metrics::METRICS;
+use crate::multipart;
+use crate::range::{self, ByteRange};
+use crate::request_id;
+use crate::router::Router;
+use crate::url;
+use crate::{HTTPMethod, HTTPRequest, HTTPResponse, HTTPStatusCode};
+
+/// The most repetitions `/echo/` will produce for `?count=`.
+const MAX_ECHO_COUNT: usize = 1000;
+
+/// The server's built-in routes.
+pub fn router() -> Router {
+    let mut router = Router::new();
+    router.register(HTTPMethod::GET, "/", root);
+    router.register(HTTPMethod::GET, "/health", health);
+    router.register(HTTPMethod::GET, "/metrics", metrics);
+    router.register(HTTPMethod::GET, "/echo/*", echo);
+    router.register(HTTPMethod::GET, "/user-agent", user_agent);
+    router.register(HTTPMethod::GET, "/cookies/*", cookie);
+    router.register(HTTPMethod::POST, "/form", form);
+    router.register(HTTPMethod::GET, "/files/*", get_file);
+    router.register(HTTPMethod::POST, "/files/*", post_file);
+    router.register(HTTPMethod::PUT, "/files/*", put_file);
+    router.register(HTTPMethod::PATCH, "/files/*", patch_file);
+    router.register(HTTPMethod::DELETE, "/files/*", delete_file);
+    router
+}
+
+/// Serves the served directory's index file when there is one, and an
+/// empty 200 otherwise.
+fn root(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let index = config
+        .index_names
+        .iter()
+        .filter_map(|name| resolve_path(config, served_directory(request, config), name).ok())
+        .find(|index| index.is_file());
+    match index {
+        Some(index) => serve_file(request, config, &index),
+        None => HTTPResponse::ok(),
+    }
+}
+
+/// A liveness check for load balancers that never touches the filesystem.
+fn health(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+    HTTPResponse::ok()
+        .with_content_type("application/json")
+        .with_body(b"{\"status\":\"ok\"}".to_vec())
+}
+
+fn metrics(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+    HTTPResponse::ok()
+        .with_content_type("text/plain; version=0.0.4")
+        .with_body(METRICS.render().into_bytes())
+}
+
+/// Echoes the rest of the path, repeated `?count=` times (once by default).
+fn echo(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let count = match request.query.get("count") {
+        None => 1,
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count <= MAX_ECHO_COUNT => count,
+            _ => return HTTPResponse::new(HTTPStatusCode::BadRequest),
+        },
+    };
+    // The route only matches below `/echo/`, so a bare `/echo` is a 404
+    // and `/echo/` echoes nothing.
+    let echoed = request.path.strip_prefix("/echo/").unwrap_or_default();
+    let content = url::percent_decode(echoed).repeat(count);
+    let accept = request.headers.get("accept").map_or("", String::as_str);
+    let (content_type, body) = match negotiate(accept, &["text/plain", "application/json"]) {
+        Some("application/json") => (
+            "application/json",
+            format!("{{\"message\":{}}}", json_string(&content)),
+        ),
+        Some(_) => ("text/plain", content),
+        None => return HTTPResponse::new(HTTPStatusCode::NotAcceptable).with_vary("Accept"),
+    };
+    let response = HTTPResponse::ok()
+        .with_content_type(content_type)
+        .with_vary("Accept");
+    encode_body(request, config, response, body.into_bytes())
+}
+
+/// Picks whichever of `offered` the `Accept` header rates highest, earlier
+/// ones winning ties, or `None` if it rules them all out. A missing or
+/// empty header accepts anything.
+fn negotiate<'a>(accept: &str, offered: &[&'a str]) -> Option<&'a str> {
+    if accept.trim().is_empty() {
+        return offered.first().copied();
+    }
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let mut params = range.split(';');
+            let media_range = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (media_range, quality)
+        })
+        .collect();
+    // The most specific range matching a type decides its quality.
+    let quality = |offer: &str| {
+        let (kind, _) = offer.split_once('/').unwrap_or((offer, ""));
+        let specificity = |range: &str| {
+            if range.eq_ignore_ascii_case(offer) {
+                Some(2)
+            } else if range.eq_ignore_ascii_case(&format!("{}/*", kind)) {
+                Some(1)
+            } else if range == "*/*" {
+                Some(0)
+            } else {
+                None
+            }
+        };
+        ranges
+            .iter()
+            .filter_map(|(range, quality)| Some((specificity(range)?, *quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+    let mut best: Option<(&str, f32)> = None;
+    for offer in offered {
+        let quality = quality(offer);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn user_agent(request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+    match request.headers.get("user-agent") {
+        Some(useragent) => HTTPResponse::ok()
+            .with_content_type("text/plain")
+            .with_body(useragent.as_bytes().to_vec()),
+        None => HTTPResponse::new(HTTPStatusCode::BadRequest),
+    }
+}
+
+/// Answers with the value of the cookie named by the rest of the path.
+fn cookie(request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+    let name = request.path.strip_prefix("/cookies/").unwrap_or_default();
+    match request.cookies().remove(&url::percent_decode(name)) {
+        Some(value) => HTTPResponse::ok()
+            .with_content_type("text/plain")
+            .with_body(value.into_bytes()),
+        None => HTTPResponse::new(HTTPStatusCode::NotFound),
+    }
+}
+
+/// Echoes the fields of a urlencoded form back as sorted `key=value` lines.
+fn form(request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+    let mut fields: Vec<_> = request.form().into_iter().collect();
+    fields.sort();
+    let body: String = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect();
+    HTTPResponse::ok()
+        .with_content_type("text/plain")
+        .with_body(body.into_bytes())
+}
+
+fn get_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let file = requested_file(request);
+    let mut target = match resolve_file(request, config, &file) {
+        Ok(target) if target.exists() => target,
+        Ok(_) => return missing_file(request, config),
+        // So is one whose directory is missing, but not one under a root
+        // that isn't configured at all.
+        Err(response)
+            if matches!(response.code, HTTPStatusCode::NotFound) && names_a_root(config, &file) =>
+        {
+            return missing_file(request, config)
+        }
+        Err(response) => return *response,
+    };
+    // Relative links in a directory's page resolve against the slashed
+    // URL, so the unslashed one is redirected there.
+    if target.is_dir() && config.redirect_directories && !request.path.ends_with('/') {
+        let query = request
+            .target
+            .split_once('?')
+            .map_or(String::new(), |(_, query)| format!("?{}", query));
+        return HTTPResponse::new(HTTPStatusCode::MovedPermanently)
+            .with_header("Location", format!("{}/{}", request.path, query));
+    }
+    if target.is_dir() {
+        // The first of `--index-names` present stands in for the directory.
+        let index = config
+            .index_names
+            .iter()
+            .map(|name| target.join(name))
+            .find(|index| index.is_file());
+        match index {
+            Some(index) => target = index,
+            None if config.autoindex => return directory_listing(request, config, &target),
+            None => return HTTPResponse::new(HTTPStatusCode::NotFound),
+        }
+    }
+    let response = serve_file(request, config, &target);
+    // `?download` or `?download=<name>` saves the file rather than showing
+    // it, under its own name or the one given.
+    match request.query.get("download") {
+        Some(name) if response.status() < 300 => {
+            let name = match name.as_str() {
+                "" => target.file_name().unwrap_or_default().to_string_lossy(),
+                name => name.into(),
+            };
+            response.with_header("Content-Disposition", attachment(&name))
+        }
+        _ => response,
+    }
+}
+
+/// A `Content-Disposition` value saving the body as `filename`. Quotes,
+/// backslashes and control characters, which could end the parameter or
+/// the header, are replaced; a name that isn't plain ASCII is also given
+/// percent-encoded in `filename*`.
+fn attachment(filename: &str) -> String {
+    let plain: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_control() || !c.is_ascii() => '_',
+            c => c,
+        })
+        .collect();
+    if filename.is_ascii() {
+        format!("attachment; filename=\"{}\"", plain)
+    } else {
+        let filename: String = filename.chars().filter(|c| !c.is_control()).collect();
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            plain,
+            url::percent_encode(&filename)
+        )
+    }
+}
+
+/// Serves the `--serve-file` file, whatever path was asked for.
+pub fn single_file(request: &HTTPRequest, config: &Config, file: &Path) -> HTTPResponse {
+    serve_file(request, config, file)
+}
+
+/// The 404 for a file missing from the served directory. With
+/// `--spa-fallback`, a page, a GET that accepts HTML, gets the fallback
+/// file instead so the app can route it client-side. Assets such as
+/// scripts don't ask for HTML and keep their 404.
+fn missing_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let response = HTTPResponse::new(HTTPStatusCode::NotFound);
+    let Some(fallback) = &config.spa_fallback else {
+        return response;
+    };
+    let wants_html = request.headers.get("accept").is_some_and(|accept| {
+        accept.split(',').any(|media_type| {
+            let media_type = media_type.split(';').next().unwrap_or_default();
+            media_type.trim().eq_ignore_ascii_case("text/html")
+        })
+    });
+    if wants_html {
+        serve_file(request, config, fallback).with_vary("Accept")
+    } else {
+        response.with_vary("Accept")
+    }
+}
+
+/// Renders an HTML page linking to every entry of `directory`, with file
+/// sizes, for `--autoindex`.
+fn directory_listing(request: &HTTPRequest, config: &Config, directory: &Path) -> HTTPResponse {
+    let entries =
+        fs::read_dir(directory).and_then(|entries| entries.collect::<Result<Vec<_>, _>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(_) => return HTTPResponse::new(HTTPStatusCode::Forbidden),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let base = escape_html(request.path.trim_end_matches('/'));
+    let title = escape_html(&url::percent_decode(&request.path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = entry.metadata().ok();
+        let is_dir = metadata.as_ref().is_some_and(|metadata| metadata.is_dir());
+        let size = match &metadata {
+            Some(metadata) if !is_dir => metadata.len().to_string(),
+            _ => "-".to_string(),
+        };
+        let suffix = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}/{}{}\">{}{}</a> {}</li>\n",
+            base,
+            url::percent_encode(&name),
+            suffix,
+            escape_html(&name),
+            suffix,
+            size
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    let response = HTTPResponse::ok().with_content_type("text/html");
+    encode_body(request, config, response, html.into_bytes())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Answers with the contents of the file at `target`, or 404 when it is
+/// missing or not a regular file. HEAD is answered from the metadata alone,
+/// without opening the file.
+fn serve_file(request: &HTTPRequest, config: &Config, target: &Path) -> HTTPResponse {
+    let metadata = match fs::metadata(target) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return HTTPResponse::new(HTTPStatusCode::NotFound),
+    };
+
+    let size = metadata.len();
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified().ok();
+    let with_validators = |response: HTTPResponse| {
+        let response = response.with_header("ETag", &etag);
+        match modified {
+            Some(modified) => response.with_header(
+                "Last-Modified",
+                DateTime::from_system_time(modified).to_http_date(),
+            ),
+            None => response,
+        }
+    };
+
+    // If-Modified-Since only counts when there is no If-None-Match.
+    let cached = match request.headers.get("if-none-match") {
+        Some(candidates) => etag_matches(candidates, &etag),
+        None => request
+            .headers
+            .get("if-modified-since")
+            .and_then(|since| date::parse_http_date(since))
+            .zip(modified)
+            .is_some_and(|(since, modified)| not_modified_since(modified, since)),
+    };
+    if cached && matches!(request.method, HTTPMethod::GET | HTTPMethod::HEAD) {
+        return with_validators(HTTPResponse::new(HTTPStatusCode::NotModified));
+    }
+
+    let content_type = content_type_for(&target.to_string_lossy(), config);
+    let range = request
+        .headers
+        .get("range")
+        .filter(|_| request.method == HTTPMethod::GET)
+        .filter(|_| if_range_holds(request, &etag, modified))
+        .and_then(|range| range::parse(range, size));
+    if let Some(Ok(ranges)) = &range {
+        if ranges.len() > 1 {
+            let response = HTTPResponse::new(HTTPStatusCode::PartialContent)
+                .with_header("Accept-Ranges", "bytes");
+            return byte_ranges(
+                target,
+                with_validators(response),
+                content_type,
+                size,
+                ranges,
+            );
+        }
+    }
+
+    let response = HTTPResponse::ok()
+        .with_content_type(content_type)
+        .with_header("Accept-Ranges", "bytes");
+    let response = with_validators(response);
+
+    match range {
+        Some(Ok(ranges)) if ranges.len() == 1 => {
+            return partial_content(target, response, size, ranges[0])
+        }
+        Some(Err(range::Unsatisfiable)) => {
+            return HTTPResponse::new(HTTPStatusCode::RangeNotSatisfiable)
+                .with_header("Content-Range", format!("bytes */{}", size))
+        }
+        _ => {}
+    }
+
+    // A stored `.gz` copy is sent when there is one; otherwise a file worth
+    // it is compressed as it goes out.
+    let sibling = precompressed(target);
+    if sibling.is_none() && !gzip_eligible(config, content_type, size) {
+        return attach_file(request, response, target, size);
+    }
+    let response = response.with_vary("Accept-Encoding");
+    if !accepts_gzip(request) {
+        return attach_file(request, response, target, size);
+    }
+    // The compressed body keeps the plain file's type and validators.
+    let response = response.with_header("Content-Encoding", "gzip");
+    match sibling {
+        Some((compressed, len)) => attach_file(request, response, &compressed, len),
+        None => attach_gzipped(request, response, target),
+    }
+}
+
+/// Streams the `len`-byte file at `path` as the body, or for HEAD just
+/// reports its length.
+fn attach_file(
+    request: &HTTPRequest,
+    response: HTTPResponse,
+    path: &Path,
+    len: u64,
+) -> HTTPResponse {
+    if request.method == HTTPMethod::HEAD {
+        return response.with_header("Content-Length", len);
+    }
+    match fs::File::open(path) {
+        Ok(file) => response.with_file(File::from_std(file), len),
+        Err(_) => internal_error(),
+    }
+}
+
+/// Streams the file at `path` gzipped on the fly, as chunks since the
+/// compressed length isn't known up front. HEAD gets the same headers,
+/// and so no Content-Length either.
+fn attach_gzipped(request: &HTTPRequest, response: HTTPResponse, path: &Path) -> HTTPResponse {
+    if request.method == HTTPMethod::HEAD {
+        return response.with_chunked(Box::new(tokio::io::empty()));
+    }
+    match fs::File::open(path) {
+        Ok(file) => {
+            let file = tokio::io::BufReader::new(File::from_std(file));
+            response.with_chunked(Box::new(compression::GzipReader::new(file)))
+        }
+        Err(_) => internal_error(),
+    }
+}
+
+/// A `<file>.gz` sitting next to the file, served in its place when the
+/// client accepts gzip to spare compressing on the fly.
+fn precompressed(target: &Path) -> Option<(PathBuf, u64)> {
+    let mut sibling = target.as_os_str().to_owned();
+    sibling.push(".gz");
+    let sibling = PathBuf::from(sibling);
+    let metadata = fs::metadata(&sibling).ok()?;
+    metadata.is_file().then_some((sibling, metadata.len()))
+}
+
+/// A 206 carrying just `range` of the file; never compressed, since the
+/// offsets refer to the file as stored.
+fn partial_content(
+    target: &Path,
+    response: HTTPResponse,
+    size: u64,
+    range: ByteRange,
+) -> HTTPResponse {
+    let opened = fs::File::open(target)
+        .and_then(|mut file| file.seek(SeekFrom::Start(range.start)).map(|_| file));
+    let Ok(content) = opened else {
+        return internal_error();
+    };
+    let mut response = response.with_header(
+        "Content-Range",
+        format!("bytes {}-{}/{}", range.start, range.end, size),
+    );
+    response.code = HTTPStatusCode::PartialContent;
+    response.message = response.code.reason_phrase().to_string();
+    response.with_file(File::from_std(content), range.len())
+}
+
+/// A 206 carrying several `ranges` of the file as the parts of a
+/// `multipart/byteranges` body, each with its own Content-Range. The parts
+/// are streamed from one open file rather than read into memory.
+fn byte_ranges(
+    target: &Path,
+    response: HTTPResponse,
+    content_type: &str,
+    size: u64,
+    ranges: &[ByteRange],
+) -> HTTPResponse {
+    let Ok(file) = fs::File::open(target) else {
+        return internal_error();
+    };
+    let boundary = request_id::generate();
+    let parts = ranges
+        .iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let head = format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                if i == 0 { "" } else { "\r\n" },
+                boundary,
+                content_type,
+                range.start,
+                range.end,
+                size
+            );
+            (head.into_bytes(), *range)
+        })
+        .collect();
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    response
+        .with_content_type(&format!("multipart/byteranges; boundary={}", boundary))
+        .with_parts(File::from_std(file), parts, tail.into_bytes())
+}
+
+/// A weak validator built from the file's size and modification time, so
+/// it changes whenever the file is rewritten without hashing its contents.
+fn etag_for(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "W/\"{:x}-{:x}.{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// HTTP dates have one-second resolution, so the sub-second part of the
+/// modification time is ignored.
+fn not_modified_since(modified: SystemTime, since: SystemTime) -> bool {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |modified| modified.as_secs());
+    let since = since
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    modified <= since
+}
+
+/// Changes to a file only go ahead when there is no `If-Match`, or it
+/// strongly matches the file's current ETag, or is `*` and the file
+/// exists; so a client can't overwrite an edit it hasn't seen. Our ETags
+/// are all weak, so in practice only `*` passes.
+fn if_match_holds(request: &HTTPRequest, target: &Path) -> bool {
+    let Some(candidates) = request.headers.get("if-match") else {
+        return true;
+    };
+    match fs::metadata(target) {
+        Ok(metadata) if metadata.is_file() => {
+            let etag = etag_for(&metadata);
+            candidates.split(',').any(|candidate| {
+                let candidate = candidate.trim();
+                candidate == "*" || strong_match(candidate, &etag)
+            })
+        }
+        _ => false,
+    }
+}
+
+/// A Range is only honoured when there is no `If-Range`, or it strongly
+/// matches the file's current ETag or names its exact Last-Modified date;
+/// otherwise the client's partial copy may be stale and it gets the whole
+/// file instead.
+fn if_range_holds(request: &HTTPRequest, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(condition) = request.headers.get("if-range") else {
+        return true;
+    };
+    let condition = condition.trim();
+    if condition.starts_with('"') || condition.starts_with("W/") {
+        return strong_match(condition, etag);
+    }
+    let seconds = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs())
+    };
+    match (date::parse_http_date(condition), modified) {
+        (Some(date), Some(modified)) => seconds(date) == seconds(modified),
+        _ => false,
+    }
+}
+
+/// The strong comparison that If-Match and If-Range call for: a weak tag
+/// on either side never matches.
+fn strong_match(candidate: &str, etag: &str) -> bool {
+    !candidate.starts_with("W/") && !etag.starts_with("W/") && candidate == etag
+}
+
+/// Checks an `If-None-Match` style list of entity tags against `etag`,
+/// using the weak comparison (the `W/` prefix is ignored on both sides).
+fn etag_matches(candidates: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    candidates.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+fn post_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let boundary = request
+        .headers
+        .get("content-type")
+        .and_then(|content_type| multipart::boundary(content_type));
+    let body = match decoded_body(request, config) {
+        Ok(body) => body,
+        Err(response) => return *response,
+    };
+    if let Some(boundary) = boundary {
+        return upload_files(request, config, &body, &boundary);
+    }
+    let target = match resolve_file(request, config, &requested_file(request)) {
+        Ok(target) => target,
+        Err(response) => return *response,
+    };
+    match fs::write(target, &body) {
+        Ok(()) => HTTPResponse::new(HTTPStatusCode::Created),
+        Err(_) => internal_error(),
+    }
+}
+
+/// Saves every file part of a `multipart/form-data` upload into the
+/// directory named by the request path, under the part's own filename.
+fn upload_files(
+    request: &HTTPRequest,
+    config: &Config,
+    body: &[u8],
+    boundary: &str,
+) -> HTTPResponse {
+    let bad_request = || HTTPResponse::new(HTTPStatusCode::BadRequest);
+    let Some(parts) = multipart::parse(body, boundary) else {
+        return bad_request();
+    };
+    let directory = requested_file(request);
+    for part in parts {
+        let Some(filename) = part.filename else {
+            continue;
+        };
+        // Browsers may send a full client-side path; only its last
+        // component names the file.
+        let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+        if filename.is_empty() || filename == "." || filename == ".." {
+            return bad_request();
+        }
+        let target = match resolve_file(request, config, &format!("{}/{}", directory, filename)) {
+            Ok(target) => target,
+            Err(response) => return *response,
+        };
+        if fs::write(target, &part.data).is_err() {
+            return internal_error();
+        }
+    }
+    HTTPResponse::new(HTTPStatusCode::Created)
+}
+
+/// Creates or replaces the file: 201 when it is new, 204 when an existing
+/// one was overwritten.
+fn put_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let target = match resolve_file(request, config, &requested_file(request)) {
+        Ok(target) => target,
+        Err(response) => return *response,
+    };
+    let body = match decoded_body(request, config) {
+        Ok(body) => body,
+        Err(response) => return *response,
+    };
+    if !if_match_holds(request, &target) {
+        return HTTPResponse::new(HTTPStatusCode::PreconditionFailed);
+    }
+    let existed = target.exists();
+    match fs::write(target, &body) {
+        Ok(()) if existed => HTTPResponse::new(HTTPStatusCode::NoContent),
+        Ok(()) => HTTPResponse::new(HTTPStatusCode::Created),
+        Err(_) => internal_error(),
+    }
+}
+
+/// Appends the body to an existing file; a missing one is a 404 rather
+/// than being created.
+fn patch_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let target = match resolve_file(request, config, &requested_file(request)) {
+        Ok(target) => target,
+        Err(response) => return *response,
+    };
+    if !target.is_file() {
+        return HTTPResponse::new(HTTPStatusCode::NotFound);
+    }
+    if !if_match_holds(request, &target) {
+        return HTTPResponse::new(HTTPStatusCode::PreconditionFailed);
+    }
+    let body = match decoded_body(request, config) {
+        Ok(body) => body,
+        Err(response) => return *response,
+    };
+    let appended = fs::OpenOptions::new()
+        .append(true)
+        .open(target)
+        .and_then(|mut file| file.write_all(&body));
+    match appended {
+        Ok(()) => HTTPResponse::ok(),
+        Err(_) => internal_error(),
+    }
+}
+
+fn delete_file(request: &HTTPRequest, config: &Config) -> HTTPResponse {
+    let target = match resolve_file(request, config, &requested_file(request)) {
+        Ok(target) => target,
+        Err(response) => return *response,
+    };
+    if !if_match_holds(request, &target) {
+        return HTTPResponse::new(HTTPStatusCode::PreconditionFailed);
+    }
+    match fs::remove_file(target) {
+        Ok(()) => HTTPResponse::new(HTTPStatusCode::NoContent),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            HTTPResponse::new(HTTPStatusCode::NotFound)
+        }
+        // Directories and files we lack permission on.
+        Err(_) => HTTPResponse::new(HTTPStatusCode::Forbidden),
+    }
+}
+
+/// The request body with its `Content-Encoding` undone, so uploads are
+/// stored as their plain contents. An unsupported coding is a 415, a
+/// corrupt body a 400, and one inflating past `--max-body-size` a 413.
+fn decoded_body<'a>(
+    request: &'a HTTPRequest,
+    config: &Config,
+) -> Result<Cow<'a, [u8]>, Box<HTTPResponse>> {
+    let Some(encodings) = request.headers.get("content-encoding") else {
+        return Ok(Cow::Borrowed(&request.body));
+    };
+    let mut body = Cow::Borrowed(request.body.as_slice());
+    // Codings are listed in the order they were applied.
+    for coding in encodings.rsplit(',') {
+        body = match compression::decode(coding, &body, config.max_body_size) {
+            None => {
+                let response = HTTPResponse::new(HTTPStatusCode::UnsupportedMediaType);
+                return Err(Box::new(
+                    response.with_header("Accept-Encoding", "gzip, deflate"),
+                ));
+            }
+            Some(Err(_)) => return Err(Box::new(HTTPResponse::new(HTTPStatusCode::BadRequest))),
+            Some(Ok(decoded)) if decoded.len() > config.max_body_size => {
+                return Err(Box::new(HTTPResponse::new(HTTPStatusCode::PayloadTooLarge)))
+            }
+            Some(Ok(decoded)) => Cow::Owned(decoded),
+        };
+    }
+    Ok(body)
+}
+
+/// For file operations that fail on our side, such as writing into a
+/// read-only directory.
+fn internal_error() -> HTTPResponse {
+    HTTPResponse::new(HTTPStatusCode::InternalServerError)
+}
+
+/// The decoded part of the path after `/files/`.
+fn requested_file(request: &HTTPRequest) -> String {
+    url::percent_decode(request.path.get(7..).unwrap_or_default())
+}
+
+/// Attaches `body` to `response`, gzip-compressed with the matching
+/// Content-Encoding header when the client accepts gzip and the body is
+/// worth compressing.
+fn encode_body(
+    request: &HTTPRequest,
+    config: &Config,
+    response: HTTPResponse,
+    body: Vec<u8>,
+) -> HTTPResponse {
+    let content_type = response.header("Content-Type").unwrap_or_default();
+    let len = body.len() as u64;
+    if !gzip_eligible(config, content_type, len) {
+        return response.with_body(body);
+    }
+    let response = response.with_vary("Accept-Encoding");
+    if accepts_gzip(request) {
+        if let Ok(compressed) = compression::gzip(&body) {
+            return response
+                .with_header("Content-Encoding", "gzip")
+                .with_body(compressed);
+        }
+    }
+    response.with_body(body)
+}
+
+/// A body is worth compressing when it is at least `--gzip-min-length`
+/// bytes and its type isn't compressed already.
+fn gzip_eligible(config: &Config, content_type: &str, len: u64) -> bool {
+    len >= config.gzip_min_length && compression::is_compressible(content_type)
+}
+
+fn accepts_gzip(request: &HTTPRequest) -> bool {
+    request
+        .headers
+        .get("accept-encoding")
+        .is_some_and(|encodings| compression::accepts_gzip(encodings))
+}
+
+/// Picks a Content-Type from the file extension, falling back to
+/// `--default-content-type` for anything unrecognised.
+fn content_type_for<'a>(path: &str, config: &'a Config) -> &'a str {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension);
+    match extension
+        .map(|extension| extension.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("txt") => "text/plain",
+        Some("zip") => "application/zip",
+        _ => &config.default_content_type,
+    }
+}
+
+/// The `--vhost` directory for the request's Host, or else `--directory`.
+fn served_directory<'a>(request: &HTTPRequest, config: &'a Config) -> &'a Path {
+    request
+        .host
+        .as_ref()
+        .and_then(|host| config.vhosts.get(host))
+        .map_or(Path::new(&config.directory), PathBuf::as_path)
+}
+
+/// Maps the part of the path after `/files/` onto the served directory or,
+/// when `--directory name=path` roots are configured, onto the root named
+/// by its first segment. An unknown root is a 404.
+fn resolve_file(
+    request: &HTTPRequest,
+    config: &Config,
+    file: &str,
+) -> Result<PathBuf, Box<HTTPResponse>> {
+    if config.roots.is_empty() {
+        return resolve_path(config, served_directory(request, config), file);
+    }
+    let (name, rest) = file.split_once('/').unwrap_or((file, ""));
+    match config.roots.get(name) {
+        Some(root) => resolve_path(config, root, rest),
+        None => Err(Box::new(HTTPResponse::new(HTTPStatusCode::NotFound))),
+    }
+}
+
+/// Whether `file` is under one of the `--directory name=path` roots, as
+/// `resolve_file` maps it; always so without named roots.
+fn names_a_root(config: &Config, file: &str) -> bool {
+    let (name, _) = file.split_once('/').unwrap_or((file, ""));
+    config.roots.is_empty() || config.roots.contains_key(name)
+}
+
+/// Maps `file` onto `directory`, refusing anything that resolves outside
+/// of it. The target itself need not exist yet as long as its parent
+/// directory does, so uploads can use it too. `..` may never climb out,
+/// but with `--follow-symlinks` a symlink inside the directory may point
+/// anywhere; without it, a dangling symlink is refused outright.
+fn resolve_path(
+    config: &Config,
+    directory: &Path,
+    file: &str,
+) -> Result<PathBuf, Box<HTTPResponse>> {
+    let not_found = || Box::new(HTTPResponse::new(HTTPStatusCode::NotFound));
+    let forbidden = || Box::new(HTTPResponse::new(HTTPStatusCode::Forbidden));
+    let root = directory.canonicalize().map_err(|_| not_found())?;
+    let mut requested = root.clone();
+    for component in Path::new(file).components() {
+        match component {
+            Component::Normal(name) => requested.push(name),
+            Component::ParentDir if requested != root => {
+                requested.pop();
+            }
+            Component::ParentDir => return Err(forbidden()),
+            // Joining an absolute path would replace the root entirely.
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    if config.follow_symlinks {
+        return Ok(requested);
+    }
+    let resolved = match requested.canonicalize() {
+        Ok(resolved) => resolved,
+        // A dangling symlink can't be resolved, but writing through it
+        // would create its target wherever that is.
+        Err(_) if fs::symlink_metadata(&requested).is_ok_and(|meta| meta.is_symlink()) => {
+            return Err(forbidden())
+        }
+        Err(_) => {
+            let name = requested.file_name().ok_or_else(not_found)?;
+            let parent = requested.parent().ok_or_else(not_found)?;
+            parent.canonicalize().map_err(|_| not_found())?.join(name)
+        }
+    };
+    if resolved.starts_with(&root) {
+        Ok(resolved)
+    } else {
+        Err(forbidden())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{config_for, exchange, response, scratch_dir, Response};
+
+    async fn send(config: &Config, request: &str) -> Response {
+        response(&exchange(config, request.as_bytes()).await)
+    }
+
+    /// A served directory holding `digits.txt`, `0123456789` ten times.
+    fn digits(name: &str) -> Config {
+        let dir = scratch_dir(name);
+        fs::write(dir.join("digits.txt"), "0123456789".repeat(10)).unwrap();
+        config_for(&dir)
+    }
+
+    #[tokio::test]
+    async fn several_ranges_are_sent_as_multipart_byteranges() {
+        let config = digits("ranges-multipart");
+        let response = send(
+            &config,
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nRange: bytes=0-2,95-\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 206);
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/100\r\n\r\n012\r\n\
+             --{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 95-99/100\r\n\r\n56789\r\n\
+             --{0}--\r\n",
+            boundary
+        );
+        assert_eq!(response.text(), expected);
+    }
+
+    #[tokio::test]
+    async fn repeated_ranges_are_not_amplified() {
+        let config = digits("ranges-repeated");
+        let request = format!(
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nRange: bytes={}\r\n\r\n",
+            vec!["0-0"; 1900].join(",")
+        );
+        let response = send(&config, &request).await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 0-0/100"));
+        assert_eq!(response.text(), "0");
+    }
+
+    #[tokio::test]
+    async fn too_many_ranges_get_the_whole_file() {
+        let config = digits("ranges-too-many");
+        let ranges: Vec<String> = (0..20).map(|i| format!("{0}-{0}", i * 3)).collect();
+        let request = format!(
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nRange: bytes={}\r\n\r\n",
+            ranges.join(",")
+        );
+        let response = send(&config, &request).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.len(), 100);
+    }
+
+    /// The response head up to the blank line, minus the Date header that
+    /// differs between any two responses.
+    fn head_without_date(output: &[u8]) -> String {
+        let output = String::from_utf8_lossy(output);
+        let (head, _) = output.split_once("\r\n\r\n").unwrap();
+        head.lines()
+            .filter(|line| !line.starts_with("Date:"))
+            .collect::<Vec<_>>()
+            .join("\r\n")
+    }
+
+    #[tokio::test]
+    async fn head_of_a_large_file_reports_its_size_without_a_body() {
+        let dir = scratch_dir("head-large");
+        let contents = "all work and no play\n".repeat(200_000);
+        fs::write(dir.join("large.txt"), &contents).unwrap();
+        let config = config_for(&dir);
+        let request = "/files/large.txt HTTP/1.1\r\nHost: test\r\nX-Request-Id: same\r\nConnection: close\r\n\r\n";
+
+        let head = exchange(&config, format!("HEAD {}", request).as_bytes()).await;
+        assert!(head.ends_with(b"\r\n\r\n"), "HEAD sent a body");
+        let length = format!("\r\nContent-Length: {}\r\n", contents.len());
+        assert!(String::from_utf8_lossy(&head).contains(&length));
+
+        let get = exchange(&config, format!("GET {}", request).as_bytes()).await;
+        assert_eq!(head_without_date(&head), head_without_date(&get));
+        assert_eq!(response(&get).body.len(), contents.len());
+    }
+
+    #[tokio::test]
+    async fn head_of_a_gzipped_file_matches_get_without_a_length() {
+        let dir = scratch_dir("head-gzip");
+        fs::write(
+            dir.join("large.txt"),
+            "all work and no play\n".repeat(10_000),
+        )
+        .unwrap();
+        let config = config_for(&dir);
+        let request = "/files/large.txt HTTP/1.1\r\nHost: test\r\nAccept-Encoding: gzip\r\nX-Request-Id: same\r\nConnection: close\r\n\r\n";
+
+        let head = exchange(&config, format!("HEAD {}", request).as_bytes()).await;
+        assert!(head.ends_with(b"\r\n\r\n"), "HEAD sent a body");
+        let head_text = String::from_utf8_lossy(&head);
+        assert!(head_text.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(head_text.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!head_text.contains("Content-Length"));
+
+        let get = exchange(&config, format!("GET {}", request).as_bytes()).await;
+        assert_eq!(head_without_date(&head), head_without_date(&get));
+        assert_eq!(response(&get).header("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn cookies_are_read_from_every_cookie_header() {
+        let config = config_for(Path::new("."));
+        let request =
+            "GET /cookies/c HTTP/1.1\r\nHost: test\r\nCookie: a=1; b=2\r\nCookie: c=x=y\r\n\r\n";
+        let response = send(&config, request).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "x=y");
+        let request = "GET /cookies/d HTTP/1.1\r\nHost: test\r\nCookie: a=1; b=2; c=x=y\r\n\r\n";
+        assert_eq!(send(&config, request).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn binary_files_are_served_byte_for_byte() {
+        let dir = scratch_dir("binary");
+        let contents: Vec<u8> = (0..=255u8).rev().chain(0..=255).collect();
+        fs::write(dir.join("all-bytes.bin"), &contents).unwrap();
+        let config = config_for(&dir);
+        let response = send(
+            &config,
+            "GET /files/all-bytes.bin HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some("512"));
+        assert_eq!(response.body, contents);
+    }
+
+    #[tokio::test]
+    async fn echoes_are_gzipped_only_for_clients_that_accept_it() {
+        let config = Config {
+            gzip_min_length: 0,
+            ..config_for(Path::new("."))
+        };
+        let request = |accept_encoding: &str| {
+            format!(
+                "GET /echo/squeeze-me?count=50 HTTP/1.1\r\nHost: test\r\n{}\r\n",
+                accept_encoding
+            )
+        };
+        let expected = "squeeze-me".repeat(50);
+
+        let response = send(&config, &request("Accept-Encoding: gzip, deflate\r\n")).await;
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        let length = response.body.len().to_string();
+        assert_eq!(response.header("Content-Length"), Some(length.as_str()));
+        let decoded = compression::decode("gzip", &response.body, expected.len()).unwrap();
+        assert_eq!(decoded.unwrap(), expected.as_bytes());
+
+        for accept_encoding in ["Accept-Encoding: identity\r\n", ""] {
+            let response = send(&config, &request(accept_encoding)).await;
+            assert_eq!(response.header("Content-Encoding"), None);
+            assert_eq!(response.text(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_gzipped_for_clients_that_accept_it() {
+        let dir = scratch_dir("gzip-files");
+        let contents = "<p>squeeze me</p>\n".repeat(500);
+        fs::write(dir.join("page.html"), &contents).unwrap();
+        let config = config_for(&dir);
+        let request = |accept_encoding: &str| {
+            format!(
+                "GET /files/page.html HTTP/1.1\r\nHost: test\r\n{}\r\n",
+                accept_encoding
+            )
+        };
+
+        let response = send(&config, &request("Accept-Encoding: gzip\r\n")).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        assert!(response.body.len() < contents.len());
+        let decoded = compression::decode("gzip", &response.body, contents.len()).unwrap();
+        assert_eq!(decoded.unwrap(), contents.as_bytes());
+
+        let response = send(&config, &request("")).await;
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.text(), contents);
+    }
+
+    #[tokio::test]
+    async fn images_and_archives_are_not_gzipped_again() {
+        let dir = scratch_dir("gzip-skip");
+        let contents = vec![b'z'; 4096];
+        for name in ["photo.png", "bundle.zip"] {
+            fs::write(dir.join(name), &contents).unwrap();
+        }
+        let config = config_for(&dir);
+        for name in ["photo.png", "bundle.zip"] {
+            let request = format!(
+                "GET /files/{} HTTP/1.1\r\nHost: test\r\nAccept-Encoding: gzip\r\n\r\n",
+                name
+            );
+            let response = send(&config, &request).await;
+            assert_eq!(response.status, 200, "{}", name);
+            assert_eq!(response.header("Content-Encoding"), None, "{}", name);
+            assert_eq!(response.header("Content-Length"), Some("4096"), "{}", name);
+            assert_eq!(response.body, contents);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_served_with_the_type_their_extension_names() {
+        let dir = scratch_dir("content-types");
+        fs::write(dir.join("page.html"), "<p>hi</p>").unwrap();
+        fs::write(dir.join("data.unknownext"), "??").unwrap();
+        fs::write(dir.join("PHOTO.JPG"), "not really").unwrap();
+        let config = config_for(&dir);
+        for (file, content_type) in [
+            ("page.html", "text/html"),
+            ("data.unknownext", "application/octet-stream"),
+            ("PHOTO.JPG", "image/jpeg"),
+        ] {
+            let request = format!("GET /files/{} HTTP/1.1\r\nHost: test\r\n\r\n", file);
+            let response = send(&config, &request).await;
+            assert_eq!(response.status, 200);
+            assert_eq!(
+                response.header("Content-Type"),
+                Some(content_type),
+                "{}",
+                file
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_cannot_climb_out_of_the_served_directory() {
+        let outer = scratch_dir("traversal");
+        let dir = outer.join("served");
+        fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+        fs::write(outer.join("secret.txt"), "secret").unwrap();
+        fs::write(dir.join("nested/deeper/page.txt"), "nested").unwrap();
+        let config = config_for(&dir);
+        for path in [
+            "../secret.txt",
+            "..%2fsecret.txt",
+            "%2e%2e/secret.txt",
+            "nested/../../secret.txt",
+            "%2F..%2Fsecret.txt",
+        ] {
+            let request = format!("GET /files/{} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+            let response = send(&config, &request).await;
+            assert_eq!(response.status, 403, "{}", path);
+            assert!(!response.text().contains("secret"), "{}", path);
+        }
+        let response = send(
+            &config,
+            "GET /files/nested/deeper/page.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "nested");
+    }
+
+    #[tokio::test]
+    async fn head_matches_get_without_the_body() {
+        let config = digits("head");
+        let output = exchange(
+            &config,
+            b"HEAD /files/digits.txt HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.contains("\r\nContent-Length: 100\r\n"));
+        assert!(output.ends_with("\r\n\r\n"), "HEAD sent a body");
+
+        let output = exchange(
+            &config,
+            b"HEAD /echo/abc HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\r\nContent-Length: 3\r\n"));
+        assert!(output.ends_with("\r\n\r\n"), "HEAD sent a body");
+
+        let output = exchange(
+            &config,
+            b"HEAD /files/missing.txt HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            output
+        );
+        assert!(output.ends_with("\r\n\r\n"), "HEAD sent a body");
+    }
+
+    #[tokio::test]
+    async fn deleted_files_are_gone() {
+        let outer = scratch_dir("delete");
+        let dir = outer.join("served");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("doomed.txt"), "bye").unwrap();
+        fs::write(outer.join("outside.txt"), "stay").unwrap();
+        let config = config_for(&dir);
+        let delete = |path: &str| format!("DELETE /files/{} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+
+        assert_eq!(send(&config, &delete("doomed.txt")).await.status, 204);
+        assert!(!dir.join("doomed.txt").exists());
+        let response = send(
+            &config,
+            "GET /files/doomed.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 404);
+
+        assert_eq!(send(&config, &delete("doomed.txt")).await.status, 404);
+        assert_eq!(send(&config, &delete("..%2Foutside.txt")).await.status, 403);
+        assert!(outer.join("outside.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn echoed_and_requested_paths_are_percent_decoded() {
+        let dir = scratch_dir("percent-decoded");
+        fs::create_dir(dir.join("sub dir")).unwrap();
+        fs::write(dir.join("sub dir/file.txt"), "found").unwrap();
+        let config = config_for(&dir);
+        for (path, echoed) in [
+            ("hello%20world", "hello world"),
+            ("a%2Fb", "a/b"),
+            ("100%ZZ", "100%ZZ"),
+        ] {
+            let request = format!("GET /echo/{} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+            assert_eq!(send(&config, &request).await.text(), echoed);
+        }
+        let response = send(
+            &config,
+            "GET /files/sub%20dir%2Ffile.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "found");
+    }
+
+    #[tokio::test]
+    async fn disallowed_methods_on_echo_list_the_allowed_ones() {
+        let config = config_for(Path::new("."));
+        let response = send(
+            &config,
+            "POST /echo/abc HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn multi_megabyte_files_arrive_whole() {
+        let dir = scratch_dir("streamed");
+        let contents: Vec<u8> = (0..3 * 1024 * 1024 + 17u32)
+            .map(|i| (i % 241) as u8)
+            .collect();
+        fs::write(dir.join("big.bin"), &contents).unwrap();
+        let config = config_for(&dir);
+        let response = send(&config, "GET /files/big.bin HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        let length = contents.len().to_string();
+        assert_eq!(response.header("Content-Length"), Some(length.as_str()));
+        assert!(response.body == contents, "body differs");
+    }
+
+    #[tokio::test]
+    async fn the_root_serves_index_html_when_there_is_one() {
+        let dir = scratch_dir("index");
+        let config = config_for(&dir);
+        let response = send(&config, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty());
+
+        fs::write(dir.join("index.html"), "<h1>welcome</h1>").unwrap();
+        let response = send(&config, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        assert_eq!(response.text(), "<h1>welcome</h1>");
+    }
+
+    #[tokio::test]
+    async fn autoindex_lists_a_directory_without_an_index() {
+        let dir = scratch_dir("autoindex");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/first.txt"), "1").unwrap();
+        fs::write(dir.join("sub/<second>.txt"), "22").unwrap();
+        let request = "GET /files/sub/ HTTP/1.1\r\nHost: test\r\n\r\n";
+
+        let off = send(&config_for(&dir), request).await;
+        assert_eq!(off.status, 404);
+
+        let config = Config {
+            autoindex: true,
+            ..config_for(&dir)
+        };
+        let listing = send(&config, request).await;
+        assert_eq!(listing.status, 200);
+        assert_eq!(listing.header("Content-Type"), Some("text/html"));
+        let html = listing.text();
+        assert!(html.contains("first.txt"), "{}", html);
+        assert!(html.contains("&lt;second&gt;.txt"), "{}", html);
+        assert!(!html.contains("<second>"), "{}", html);
+    }
+
+    #[tokio::test]
+    async fn single_ranges_get_a_206_with_their_slice() {
+        let config = digits("ranges-single");
+        let request = |range: &str| {
+            format!(
+                "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nRange: {}\r\n\r\n",
+                range
+            )
+        };
+        let whole = send(
+            &config,
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(whole.status, 200);
+        assert_eq!(whole.header("Accept-Ranges"), Some("bytes"));
+
+        let response = send(&config, &request("bytes=10-14")).await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 10-14/100"));
+        assert_eq!(response.text(), "01234");
+
+        let response = send(&config, &request("bytes=97-")).await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 97-99/100"));
+        assert_eq!(response.text(), "789");
+
+        let response = send(&config, &request("bytes=100-200")).await;
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */100"));
+    }
+
+    #[tokio::test]
+    async fn a_matching_if_none_match_gets_a_304() {
+        let config = digits("etag");
+        let first = send(
+            &config,
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(first.status, 200);
+        let etag = first.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+
+        let request = |etag: &str| {
+            format!(
+                "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nIf-None-Match: {}\r\n\r\n",
+                etag
+            )
+        };
+        let cached = send(&config, &request(&etag)).await;
+        assert_eq!(cached.status, 304);
+        assert_eq!(cached.header("ETag"), Some(etag.as_str()));
+        assert!(cached.body.is_empty());
+
+        let stale = send(&config, &request("W/\"0-0\"")).await;
+        assert_eq!(stale.status, 200);
+        assert_eq!(stale.body.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn if_modified_since_gets_a_304_for_an_unchanged_file() {
+        let config = digits("last-modified");
+        let first = send(
+            &config,
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let last_modified = first.header("Last-Modified").unwrap().to_string();
+        assert!(
+            date::parse_http_date(&last_modified).is_some(),
+            "{}",
+            last_modified
+        );
+
+        let request = |since: &str| {
+            format!(
+                "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nIf-Modified-Since: {}\r\n\r\n",
+                since
+            )
+        };
+        let cached = send(&config, &request(&last_modified)).await;
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+
+        let modified = send(&config, &request("Mon, 01 Jan 1990 00:00:00 GMT")).await;
+        assert_eq!(modified.status, 200);
+        let malformed = send(&config, &request("last tuesday")).await;
+        assert_eq!(malformed.status, 200);
+        assert_eq!(malformed.body.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn options_star_lists_every_method_served() {
+        let config = config_for(Path::new("."));
+        let response = send(&config, "OPTIONS * HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            response.header("Allow"),
+            Some("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS")
+        );
+        let response = send(&config, "OPTIONS /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn put_creates_then_replaces() {
+        let dir = scratch_dir("put");
+        let config = config_for(&dir);
+        let put = |body: &str| {
+            format!(
+                "PUT /files/note.txt HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        };
+        assert_eq!(send(&config, &put("first draft")).await.status, 201);
+        assert_eq!(fs::read(dir.join("note.txt")).unwrap(), b"first draft");
+        assert_eq!(send(&config, &put("final")).await.status, 204);
+        assert_eq!(fs::read(dir.join("note.txt")).unwrap(), b"final");
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_save_each_file_under_its_name() {
+        let dir = scratch_dir("multipart-upload");
+        fs::create_dir(dir.join("uploads")).unwrap();
+        let config = config_for(&dir);
+        let body = "--form\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\docs\\\\report.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            quarterly numbers\r\n\
+            --form--\r\n";
+        let request = format!(
+            "POST /files/uploads HTTP/1.1\r\nHost: test\r\n\
+             Content-Type: multipart/form-data; boundary=form\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        assert_eq!(send(&config, &request).await.status, 201);
+        assert_eq!(
+            fs::read(dir.join("uploads/report.txt")).unwrap(),
+            b"quarterly numbers"
+        );
+
+        let malformed = "POST /files/uploads HTTP/1.1\r\nHost: test\r\n\
+            Content-Type: multipart/form-data; boundary=form\r\nContent-Length: 6\r\n\r\n--form";
+        assert_eq!(send(&config, malformed).await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn form_posts_are_decoded_into_fields() {
+        let config = config_for(Path::new("."));
+        let body = "name=alice&age=30&note=fish%26chips";
+        let post = |content_type: &str| {
+            format!(
+                "POST /form HTTP/1.1\r\nHost: test\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            )
+        };
+        let response = send(
+            &config,
+            &post("application/x-www-form-urlencoded; charset=utf-8"),
+        )
+        .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "age=30\nname=alice\nnote=fish&chips\n");
+        // Only a urlencoded body is a form.
+        let response = send(&config, &post("text/plain")).await;
+        assert_eq!(response.text(), "");
+    }
+
+    #[tokio::test]
+    async fn named_roots_serve_their_own_files() {
+        let dir = scratch_dir("named-roots");
+        for root in ["docs", "assets"] {
+            fs::create_dir(dir.join(root)).unwrap();
+            fs::write(dir.join(root).join("which.txt"), root).unwrap();
+        }
+        let config = Config {
+            roots: ["docs", "assets"]
+                .into_iter()
+                .map(|root| (root.to_string(), dir.join(root)))
+                .collect(),
+            ..config_for(Path::new("/nonexistent"))
+        };
+        for root in ["docs", "assets"] {
+            let request = format!(
+                "GET /files/{}/which.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+                root
+            );
+            let response = send(&config, &request).await;
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), root);
+        }
+        let response = send(
+            &config,
+            "GET /files/other/which.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_internal_errors() {
+        let dir = scratch_dir("write-fails");
+        fs::create_dir(dir.join("a-directory")).unwrap();
+        fs::write(dir.join("a-file"), "").unwrap();
+        let config = config_for(&dir);
+        // Permissions don't stop root, so use paths nobody can write to.
+        for path in ["a-directory", "a-file/child.txt"] {
+            let request = format!(
+                "POST /files/{} HTTP/1.1\r\nHost: test\r\nContent-Length: 4\r\n\r\ndata",
+                path
+            );
+            assert_eq!(send(&config, &request).await.status, 500, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn if_range_resumes_only_an_unchanged_file() {
+        let config = digits("if-range");
+        let first = send(
+            &config,
+            "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let etag = first.header("ETag").unwrap().to_string();
+        let last_modified = first.header("Last-Modified").unwrap().to_string();
+        let resume = |validator: &str| {
+            format!(
+                "GET /files/digits.txt HTTP/1.1\r\nHost: test\r\nRange: bytes=90-\r\n\
+                 If-Range: {}\r\n\r\n",
+                validator
+            )
+        };
+        let response = send(&config, &resume(&last_modified)).await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.text(), "0123456789");
+        // If-Range compares strongly, so even the current weak tag fails.
+        assert!(etag.starts_with("W/"), "{}", etag);
+        for validator in [
+            etag.as_str(),
+            "W/\"stale\"",
+            "Mon, 01 Jan 1990 00:00:00 GMT",
+        ] {
+            let response = send(&config, &resume(validator)).await;
+            assert_eq!(response.status, 200, "{}", validator);
+            assert_eq!(response.body.len(), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_answers_without_a_served_directory() {
+        let config = config_for(Path::new("/nonexistent/directory"));
+        let response = send(&config, "GET /health HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(response.text(), r#"{"status":"ok"}"#);
+    }
+
+    #[tokio::test]
+    async fn echo_repeats_up_to_the_cap() {
+        let config = config_for(Path::new("."));
+        let echo = |query: &str| format!("GET /echo/ab{} HTTP/1.1\r\nHost: test\r\n\r\n", query);
+        assert_eq!(send(&config, &echo("")).await.text(), "ab");
+        assert_eq!(send(&config, &echo("?count=3")).await.text(), "ababab");
+        assert_eq!(send(&config, &echo("?count=0")).await.text(), "");
+        let most = format!("?count={}", MAX_ECHO_COUNT);
+        assert_eq!(
+            send(&config, &echo(&most)).await.body.len(),
+            2 * MAX_ECHO_COUNT
+        );
+        let too_many = format!("?count={}", MAX_ECHO_COUNT + 1);
+        for query in ["?count=-1", "?count=lots", "?count=", too_many.as_str()] {
+            assert_eq!(send(&config, &echo(query)).await.status, 400, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_under_the_gzip_threshold_are_sent_as_is() {
+        let config = config_for(Path::new("."));
+        let echo = |count: usize| {
+            format!(
+                "GET /echo/compress-?count={} HTTP/1.1\r\nHost: test\r\nAccept-Encoding: gzip\r\n\r\n",
+                count
+            )
+        };
+        // At 9 bytes a repeat, 113 fall just short of the default
+        // 1024-byte threshold and 114 reach it.
+        let small = send(&config, &echo(113)).await;
+        assert_eq!(small.header("Content-Encoding"), None);
+        assert_eq!(small.body.len(), 1017);
+        let large = send(&config, &echo(114)).await;
+        assert_eq!(large.header("Content-Encoding"), Some("gzip"));
+        assert!(large.body.len() < 1026);
+    }
+
+    #[tokio::test]
+    async fn patch_appends_to_an_existing_file_only() {
+        let dir = scratch_dir("patch");
+        fs::write(dir.join("log.txt"), "line one\n").unwrap();
+        let config = config_for(&dir);
+        let patch = |name: &str| {
+            format!(
+                "PATCH /files/{} HTTP/1.1\r\nHost: test\r\nContent-Length: 9\r\n\r\nline two\n",
+                name
+            )
+        };
+        assert_eq!(send(&config, &patch("log.txt")).await.status, 200);
+        assert_eq!(
+            fs::read_to_string(dir.join("log.txt")).unwrap(),
+            "line one\nline two\n"
+        );
+        assert_eq!(send(&config, &patch("missing.txt")).await.status, 404);
+        assert!(!dir.join("missing.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn precompressed_siblings_are_served_to_gzip_clients() {
+        let dir = scratch_dir("precompressed");
+        let script = "console.log('hi');\n".repeat(100);
+        fs::write(dir.join("app.js"), &script).unwrap();
+        fs::write(
+            dir.join("app.js.gz"),
+            compression::gzip(script.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let config = config_for(&dir);
+        let get = |name: &str, accept_encoding: &str| {
+            format!(
+                "GET /files/{} HTTP/1.1\r\nHost: test\r\nAccept-Encoding: {}\r\n\r\n",
+                name, accept_encoding
+            )
+        };
+
+        let response = send(&config, &get("app.js", "gzip")).await;
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("application/javascript")
+        );
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, fs::read(dir.join("app.js.gz")).unwrap());
+
+        let response = send(&config, &get("app.js", "identity")).await;
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.text(), script);
+    }
+
+    #[tokio::test]
+    async fn unknown_extensions_get_the_configured_default_type() {
+        let dir = scratch_dir("default-type");
+        fs::write(dir.join("notes.unknownext"), "plain words").unwrap();
+        fs::write(dir.join("page.html"), "<p></p>").unwrap();
+        let config = Config {
+            default_content_type: "text/plain".to_string(),
+            ..config_for(&dir)
+        };
+        for (file, content_type) in [
+            ("notes.unknownext", "text/plain"),
+            ("page.html", "text/html"),
+        ] {
+            let request = format!("GET /files/{} HTTP/1.1\r\nHost: test\r\n\r\n", file);
+            let response = send(&config, &request).await;
+            assert_eq!(
+                response.header("Content-Type"),
+                Some(content_type),
+                "{}",
+                file
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_needs_its_slash_but_not_content() {
+        let config = config_for(Path::new("."));
+        let echo = |path: &str| format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+        assert_eq!(send(&config, &echo("/echo")).await.status, 404);
+        let empty = send(&config, &echo("/echo/")).await;
+        assert_eq!(empty.status, 200);
+        assert_eq!(empty.header("Content-Length"), Some("0"));
+        assert_eq!(send(&config, &echo("/echo/x")).await.text(), "x");
+    }
+
+    #[tokio::test]
+    async fn compressed_uploads_are_stored_decompressed() {
+        use flate2::write::ZlibEncoder;
+
+        let dir = scratch_dir("compressed-upload");
+        let config = config_for(&dir);
+        let contents = b"the same line, over and over\n".repeat(50);
+        let mut deflated = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        deflated.write_all(&contents).unwrap();
+        for (name, coding, body) in [
+            ("gzipped.txt", "gzip", compression::gzip(&contents).unwrap()),
+            ("deflated.txt", "deflate", deflated.finish().unwrap()),
+        ] {
+            let mut request = format!(
+                "POST /files/{} HTTP/1.1\r\nHost: test\r\nContent-Encoding: {}\r\n\
+                 Content-Length: {}\r\n\r\n",
+                name,
+                coding,
+                body.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(&body);
+            assert_eq!(response(&exchange(&config, &request).await).status, 201);
+            assert_eq!(fs::read(dir.join(name)).unwrap(), contents, "{}", name);
+        }
+
+        let request = "POST /files/brotli.txt HTTP/1.1\r\nHost: test\r\nContent-Encoding: br\r\n\
+                       Content-Length: 4\r\n\r\nabcd";
+        assert_eq!(send(&config, request).await.status, 415);
+        assert!(!dir.join("brotli.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn the_first_existing_index_name_is_served() {
+        let dir = scratch_dir("index-names");
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/default.html"), "default").unwrap();
+        fs::write(dir.join("docs/fallback.html"), "fallback").unwrap();
+        let config = Config {
+            index_names: ["index.html", "default.html", "fallback.html"]
+                .map(String::from)
+                .to_vec(),
+            ..config_for(&dir)
+        };
+        let response = send(&config, "GET /files/docs/ HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "default");
+    }
+
+    #[tokio::test]
+    async fn virtual_hosts_serve_their_own_directories() {
+        let dir = scratch_dir("vhosts");
+        for site in ["default", "blog", "shop"] {
+            fs::create_dir(dir.join(site)).unwrap();
+            fs::write(dir.join(site).join("site.txt"), site).unwrap();
+        }
+        let config = Config {
+            vhosts: [("blog.test", "blog"), ("shop.test", "shop")]
+                .into_iter()
+                .map(|(host, site)| (host.to_string(), dir.join(site)))
+                .collect(),
+            ..config_for(&dir.join("default"))
+        };
+        for (host, site) in [
+            ("blog.test", "blog"),
+            ("SHOP.test:4221", "shop"),
+            ("unknown.test", "default"),
+        ] {
+            let request = format!("GET /files/site.txt HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            assert_eq!(send(&config, &request).await.text(), site, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn download_asks_for_an_attachment() {
+        let config = digits("download");
+        let get = |query: &str| {
+            format!(
+                "GET /files/digits.txt{} HTTP/1.1\r\nHost: test\r\n\r\n",
+                query
+            )
+        };
+        let inline = send(&config, &get("")).await;
+        assert_eq!(inline.header("Content-Disposition"), None);
+        let named = send(&config, &get("?download")).await;
+        assert_eq!(
+            named.header("Content-Disposition"),
+            Some("attachment; filename=\"digits.txt\"")
+        );
+        let renamed = send(&config, &get("?download=report%202024.txt")).await;
+        assert_eq!(
+            renamed.header("Content-Disposition"),
+            Some("attachment; filename=\"report 2024.txt\"")
+        );
+        assert_eq!(renamed.body.len(), 100);
+    }
+
+    #[test]
+    fn attachment_names_cannot_break_out_of_the_header() {
+        assert_eq!(
+            attachment("a\"b\\c\r\nSet-Cookie: x=1"),
+            "attachment; filename=\"a_b_c__Set-Cookie: x=1\""
+        );
+        assert_eq!(
+            attachment("café.txt"),
+            "attachment; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_are_scraped_in_the_prometheus_format() {
+        let config = config_for(Path::new("."));
+        let response = send(&config, "GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/plain; version=0.0.4")
+        );
+        let text = response.text();
+        assert!(
+            text.contains("# TYPE http_requests_total counter\n"),
+            "{}",
+            text
+        );
+        assert!(text
+            .lines()
+            .filter_map(|line| line.strip_prefix("http_requests_total "))
+            .all(|count| count.parse::<u64>().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn missing_pages_fall_back_to_the_app_but_assets_do_not() {
+        let dir = scratch_dir("spa");
+        fs::write(dir.join("index.html"), "<div id=app></div>").unwrap();
+        let config = Config {
+            spa_fallback: Some(dir.join("index.html")),
+            ..config_for(&dir)
+        };
+        let get = |path: &str, accept: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nHost: test\r\nAccept: {}\r\n\r\n",
+                path, accept
+            )
+        };
+        let html = "text/html,application/xhtml+xml;q=0.9";
+        let page = send(&config, &get("/files/app/route", html)).await;
+        assert_eq!(page.status, 200);
+        assert_eq!(page.text(), "<div id=app></div>");
+        assert_eq!(page.header("Vary"), Some("Accept"));
+
+        let asset = send(&config, &get("/files/asset.js", "*/*")).await;
+        assert_eq!(asset.status, 404);
+        assert_eq!(asset.header("Vary"), Some("Accept"));
+
+        // Only files missing from the served directory fall back.
+        for path in ["/app/route", "/cookies/session"] {
+            let not_found = send(&config, &get(path, html)).await;
+            assert_eq!(not_found.status, 404, "{}", path);
+        }
+        let config = Config {
+            roots: [("docs".to_string(), dir.clone())].into(),
+            ..config
+        };
+        let unknown_root = send(&config, &get("/files/blog/post", html)).await;
+        assert_eq!(unknown_root.status, 404);
+    }
+
+    #[tokio::test]
+    async fn echo_negotiates_json_text_or_nothing() {
+        let config = config_for(&scratch_dir("echo-accept"));
+        let echo = |accept: &str| {
+            format!(
+                "GET /echo/say%20\"hi\" HTTP/1.1\r\nHost: test\r\nAccept: {}\r\n\r\n",
+                accept
+            )
+        };
+        let json = send(&config, &echo("text/plain;q=0.5, application/json")).await;
+        assert_eq!(json.status, 200);
+        assert_eq!(json.header("Content-Type"), Some("application/json"));
+        assert_eq!(json.text(), r#"{"message":"say \"hi\""}"#);
+
+        let text = send(&config, &echo("text/*")).await;
+        assert_eq!(text.status, 200);
+        assert_eq!(text.header("Content-Type"), Some("text/plain"));
+        assert_eq!(text.text(), "say \"hi\"");
+
+        let refused = send(&config, &echo("image/png, application/json;q=0")).await;
+        assert_eq!(refused.status, 406);
+        assert_eq!(refused.header("Vary"), Some("Accept"));
+    }
+
+    #[tokio::test]
+    async fn changes_only_go_ahead_when_if_match_holds() {
+        let dir = scratch_dir("if-match");
+        fs::write(dir.join("notes.txt"), "first draft").unwrap();
+        let config = config_for(&dir);
+        let change = |method: &str, file: &str, if_match: &str| {
+            format!(
+                "{} /files/{} HTTP/1.1\r\nHost: test\r\nIf-Match: {}\r\nContent-Length: 6\r\n\r\nedited",
+                method, file, if_match
+            )
+        };
+        let fetched = send(
+            &config,
+            "GET /files/notes.txt HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let weak = fetched.header("ETag").unwrap().to_string();
+        assert!(weak.starts_with("W/"), "{}", weak);
+
+        // If-Match compares strongly, so even the current weak tag fails.
+        for if_match in ["\"stale\"", weak.as_str()] {
+            let refused = send(&config, &change("PUT", "notes.txt", if_match)).await;
+            assert_eq!(refused.status, 412, "{}", if_match);
+            let refused = send(&config, &change("DELETE", "notes.txt", if_match)).await;
+            assert_eq!(refused.status, 412, "{}", if_match);
+        }
+        assert_eq!(
+            fs::read_to_string(dir.join("notes.txt")).unwrap(),
+            "first draft"
+        );
+
+        let missing = send(&config, &change("PUT", "new.txt", "*")).await;
+        assert_eq!(missing.status, 412);
+        assert!(!dir.join("new.txt").exists());
+
+        let existing = send(&config, &change("PUT", "notes.txt", "*")).await;
+        assert_eq!(existing.status, 204);
+        assert_eq!(fs::read_to_string(dir.join("notes.txt")).unwrap(), "edited");
+        let deleted = send(&config, &change("DELETE", "notes.txt", "*")).await;
+        assert_eq!(deleted.status, 204);
+        assert!(!dir.join("notes.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn unslashed_directories_are_redirected_unless_disabled() {
+        let dir = scratch_dir("dir-redirect");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.html"), "docs home").unwrap();
+        let config = config_for(&dir);
+        let request = "GET /files/docs?lang=en HTTP/1.1\r\nHost: test\r\n\r\n";
+        let moved = send(&config, request).await;
+        assert_eq!(moved.status, 301);
+        assert_eq!(moved.header("Location"), Some("/files/docs/?lang=en"));
+
+        let slashed = send(&config, "GET /files/docs/ HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(slashed.status, 200);
+        assert_eq!(slashed.text(), "docs home");
+
+        let config = Config {
+            redirect_directories: false,
+            ..config
+        };
+        let served = send(&config, request).await;
+        assert_eq!(served.status, 200);
+        assert_eq!(served.text(), "docs home");
+    }
+
+    #[tokio::test]
+    async fn negotiated_responses_say_what_they_vary_on() {
+        let dir = scratch_dir("vary");
+        fs::write(dir.join("big.txt"), "vary ".repeat(400)).unwrap();
+        let config = config_for(&dir);
+        let gzipped = send(
+            &config,
+            "GET /files/big.txt HTTP/1.1\r\nHost: test\r\nAccept-Encoding: gzip\r\n\r\n",
+        )
+        .await;
+        assert_eq!(gzipped.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(gzipped.header("Vary"), Some("Accept-Encoding"));
+
+        let echoed = send(
+            &config,
+            "GET /echo/hi HTTP/1.1\r\nHost: test\r\nAccept: application/json\r\n\r\n",
+        )
+        .await;
+        assert_eq!(echoed.header("Vary"), Some("Accept"));
+
+        let both = "compress-".repeat(114);
+        let request = format!(
+            "GET /echo/{} HTTP/1.1\r\nHost: test\r\nAccept: text/plain\r\nAccept-Encoding: gzip\r\n\r\n",
+            both
+        );
+        let both = send(&config, &request).await;
+        assert_eq!(both.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(both.header("Vary"), Some("Accept, Accept-Encoding"));
+    }
+
+    #[cfg(unix)]
+    mod symlinks {
+        use super::*;
+
+        use std::os::unix::fs::symlink;
+
+        /// A served directory holding `link`, a symlink to a file outside
+        /// it, and `dangling`, a symlink to a missing file outside it.
+        fn linked(name: &str) -> (PathBuf, PathBuf) {
+            let scratch = scratch_dir(name);
+            let (root, outside) = (scratch.join("root"), scratch.join("outside"));
+            fs::create_dir_all(&root).unwrap();
+            fs::create_dir_all(&outside).unwrap();
+            fs::write(outside.join("secret.txt"), "outside").unwrap();
+            symlink(outside.join("secret.txt"), root.join("link")).unwrap();
+            symlink(outside.join("pwned"), root.join("dangling")).unwrap();
+            (root, outside)
+        }
+
+        #[tokio::test]
+        async fn links_out_of_the_root_are_refused_by_default() {
+            let (root, _) = linked("symlink-refused");
+            let config = config_for(&root);
+            let response = send(&config, "GET /files/link HTTP/1.1\r\nHost: test\r\n\r\n").await;
+            assert_eq!(response.status, 403);
+        }
+
+        #[tokio::test]
+        async fn links_out_of_the_root_are_followed_with_the_flag() {
+            let (root, _) = linked("symlink-followed");
+            let config = Config {
+                follow_symlinks: true,
+                ..config_for(&root)
+            };
+            let response = send(&config, "GET /files/link HTTP/1.1\r\nHost: test\r\n\r\n").await;
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), "outside");
+        }
+
+        #[tokio::test]
+        async fn writes_through_a_dangling_link_are_refused() {
+            let (root, outside) = linked("symlink-dangling");
+            let config = config_for(&root);
+            let response = send(
+                &config,
+                "PUT /files/dangling HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\r\npwned",
+            )
+            .await;
+            assert_eq!(response.status, 403);
+            assert!(!outside.join("pwned").exists());
+        }
+
+        #[tokio::test]
+        async fn links_within_the_root_are_served() {
+            let (root, _) = linked("symlink-within");
+            fs::write(root.join("real.txt"), "inside").unwrap();
+            symlink(root.join("real.txt"), root.join("alias")).unwrap();
+            let config = config_for(&root);
+            let response = send(&config, "GET /files/alias HTTP/1.1\r\nHost: test\r\n\r\n").await;
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), "inside");
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::config::Config;
+
+/// An accepted connection over either transport.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Who is on the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Peers on a Unix socket have no address of their own.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix,
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(address) => Some(address.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(address) => address.fmt(f),
+            Peer::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds `--unix-socket` if given, or else TCP on `--host` and `--port`.
+    pub fn bind(config: &Config) -> io::Result<Listener> {
+        #[cfg(unix)]
+        if let Some(path) = &config.unix_socket {
+            return bind_unix(path);
+        }
+        #[cfg(not(unix))]
+        if config.unix_socket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not available on this platform",
+            ));
+        }
+        bind_tcp(config).map(Listener::Tcp)
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&self) -> io::Result<(Box<dyn Connection>, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(peer)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Binds with SO_REUSEADDR, so a restart can rebind while connections
+/// from the previous run linger in TIME_WAIT, and the `--backlog` queue.
+fn bind_tcp(config: &Config) -> io::Result<TcpListener> {
+    let address = config.address();
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(config.backlog)
+}
+
+/// A socket file left behind by an earlier run that didn't shut down
+/// cleanly is replaced, but not one a running server still listens on;
+/// any other file in the way is an error.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "another server is listening on this socket",
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    use crate::handlers;
+    use crate::metrics::Metrics;
+    use crate::tests::{config_for, response, scratch_dir};
+
+    fn socket_config(name: &str) -> Config {
+        let dir = scratch_dir(name);
+        Config {
+            unix_socket: Some(dir.join("server.sock")),
+            ..config_for(&dir)
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_a_get_over_the_socket() {
+        let config = socket_config("unix-get");
+        std::fs::write(
+            std::path::Path::new(&config.directory).join("index.html"),
+            "local",
+        )
+        .unwrap();
+        let listener = Listener::bind(&config).unwrap();
+        let router = handlers::router();
+
+        let server = async {
+            let (stream, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, Peer::Unix);
+            crate::serve(stream, peer, &config, &router, &Metrics::new(), None).await;
+        };
+        let client = async {
+            let mut stream = UnixStream::connect(config.unix_socket.as_ref().unwrap())
+                .await
+                .unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let ((), output) = tokio::join!(server, client);
+        let response = response(&output);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "local");
+
+        drop(listener);
+        assert!(!config.unix_socket.as_ref().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn a_live_socket_is_not_taken_over() {
+        let config = socket_config("unix-live");
+        let _running = Listener::bind(&config).unwrap();
+        let error = Listener::bind(&config).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn a_stale_socket_is_replaced() {
+        let config = socket_config("unix-stale");
+        let path = config.unix_socket.as_ref().unwrap();
+        // A socket whose server has gone, as a crash leaves behind.
+        drop(std::os::unix::net::UnixListener::bind(path).unwrap());
+        assert!(path.exists());
+        assert!(Listener::bind(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_port_can_be_rebound_while_its_old_connections_linger() {
+        let mut config = config_for(&scratch_dir("reuseaddr"));
+        config.port = 0;
+        let listener = bind_tcp(&config).unwrap();
+        let address = listener.local_addr().unwrap();
+        // Closing the accepted side first leaves it in TIME_WAIT, which
+        // would block a rebind without SO_REUSEADDR.
+        let client = tokio::net::TcpStream::connect(address).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        drop(accepted);
+        drop(client);
+        drop(listener);
+
+        config.port = address.port();
+        assert!(bind_tcp(&config).is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use crate::date::DateTime;
+use crate::listener::Peer;
+use crate::HTTPRequest;
+
+/// How severe a diagnostic is; each level includes the ones above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The most verbose level printed, set once at startup.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints a diagnostic to stderr as `<level>: <message>` if `level` is
+/// enabled.
+pub fn write(level: Level, message: fmt::Arguments) {
+    if enabled(level) {
+        eprintln!("{}: {}", level, message);
+    }
+}
+
+pub fn error(message: fmt::Arguments) {
+    write(Level::Error, message);
+}
+
+pub fn warn(message: fmt::Arguments) {
+    write(Level::Warn, message);
+}
+
+pub fn info(message: fmt::Arguments) {
+    write(Level::Info, message);
+}
+
+pub fn debug(message: fmt::Arguments) {
+    write(Level::Debug, message);
+}
+
+/// The layout of access lines, chosen with `--log-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The Common Log Format.
+    Common,
+    /// Apache's combined format: the common one plus the quoted Referer
+    /// and User-Agent.
+    Combined,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(Format::Common),
+            "combined" => Ok(Format::Combined),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Prints one Common Log Format style line for a handled request, with the
+/// handling time and request ID appended, e.g.
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /echo/abc HTTP/1.1" 200 68 0.412ms d42f67879deb77a1-0`.
+/// The combined format puts the Referer and User-Agent before the
+/// appended fields. Access lines go to stdout at the info level.
+pub fn access(
+    peer: Peer,
+    request: &HTTPRequest,
+    status: u16,
+    bytes: u64,
+    elapsed: Duration,
+    request_id: &str,
+    format: Format,
+) {
+    if enabled(Level::Info) {
+        println!(
+            "{}",
+            access_line(peer, request, status, bytes, elapsed, request_id, format)
+        );
+    }
+}
+
+fn access_line(
+    peer: Peer,
+    request: &HTTPRequest,
+    status: u16,
+    bytes: u64,
+    elapsed: Duration,
+    request_id: &str,
+    format: Format,
+) -> String {
+    let combined = match format {
+        Format::Common => String::new(),
+        Format::Combined => format!(
+            " {} {}",
+            quoted(request.headers.get("referer")),
+            quoted(request.headers.get("user-agent"))
+        ),
+    };
+    format!(
+        "{} - - [{}] \"{} {} {}\" {} {}{} {:.3}ms {}",
+        peer.ip()
+            .map_or_else(|| peer.to_string(), |ip| ip.to_string()),
+        DateTime::now().to_log_format(),
+        request.method,
+        request.path,
+        request.version,
+        status,
+        bytes,
+        combined,
+        elapsed.as_secs_f64() * 1000.0,
+        request_id
+    )
+}
+
+/// A header value in quotes, with `"-"` standing for a missing one.
+fn quoted(value: Option<&String>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "\"-\"".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{parse, peer};
+
+    #[tokio::test]
+    async fn common_lines_carry_the_request_status_size_and_timing() {
+        let request = parse(b"GET /echo/abc?x=1 HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let elapsed = Duration::from_micros(1500);
+        let line = access_line(peer(), &request, 200, 68, elapsed, "id-1", Format::Common);
+        let (client, rest) = line.split_once(" [").unwrap();
+        assert_eq!(client, "127.0.0.1 - -");
+        let (_, rest) = rest.split_once("] ").unwrap();
+        assert_eq!(rest, "\"GET /echo/abc HTTP/1.1\" 200 68 1.500ms id-1");
+    }
+
+    #[tokio::test]
+    async fn combined_lines_add_the_quoted_referer_and_user_agent() {
+        let request =
+            parse(b"GET / HTTP/1.1\r\nHost: test\r\nUser-Agent: say \"hi\"\r\n\r\n").await;
+        let line = access_line(
+            peer(),
+            &request,
+            404,
+            0,
+            Duration::ZERO,
+            "id-2",
+            Format::Combined,
+        );
+        assert!(
+            line.ends_with("\" 404 0 \"-\" \"say \\\"hi\\\"\" 0.000ms id-2"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn the_level_filters_out_less_severe_messages() {
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+        set_level(Level::Warn);
+        let enabled_at_warn = [Level::Error, Level::Warn, Level::Info, Level::Debug].map(enabled);
+        // Put back the default, since the level is global.
+        set_level(Level::Info);
+        assert_eq!(enabled_at_warn, [true, true, false, false]);
+    }
+
+    #[test]
+    fn levels_parse_in_any_case() {
+        assert_eq!("WARN".parse(), Ok(Level::Warn));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+        assert_eq!("verbose".parse::<Level>(), Err(()));
+    }
+
+    #[tokio::test]
+    async fn unix_socket_peers_are_logged_as_unix() {
+        let request = parse(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let line = access_line(
+            Peer::Unix,
+            &request,
+            200,
+            0,
+            Duration::ZERO,
+            "id-3",
+            Format::Common,
+        );
+        assert!(line.starts_with("unix - - ["), "{}", line);
+    }
+}
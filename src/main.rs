@@ -1,19 +1,62 @@
+mod auth;
+mod base64;
+mod compression;
+mod config;
+mod cookie;
+mod date;
+mod handlers;
+mod listener;
+mod log;
+mod metrics;
+mod multipart;
+mod proxy;
+mod range;
+mod rate_limit;
+mod request_id;
+mod router;
+mod sha1;
+mod tls;
+mod url;
+mod websocket;
+
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::str::FromStr;
-use std::{env, fmt, fs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, process};
+
+use tokio::fs::File;
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::{signal, time};
+use tokio_rustls::TlsAcceptor;
 
-use nom::AsBytes;
-use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use crate::config::Config;
+use crate::date::DateTime;
+use crate::listener::{Listener, Peer};
+use crate::metrics::{Metrics, METRICS};
+use crate::range::ByteRange;
+use crate::rate_limit::RateLimiter;
+use crate::router::Router;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 enum HTTPStatusCode {
+    SwitchingProtocols = 101,
     OK = 200,
     Created = 201,
     Accepted = 202,
     NoContent = 204,
+    PartialContent = 206,
     MovedPermanently = 301,
     Found = 302,
     NotModified = 304,
@@ -22,13 +65,106 @@ enum HTTPStatusCode {
     Forbidden = 403,
     NotFound = 404,
     MethodNotAllowed = 405,
+    NotAcceptable = 406,
     RequestTimeout = 408,
     Conflict = 409,
     Gone = 410,
+    LengthRequired = 411,
     PreconditionFailed = 412,
     PayloadTooLarge = 413,
     URITooLong = 414,
     UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    ExpectationFailed = 417,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    HTTPVersionNotSupported = 505,
+}
+
+impl TryFrom<u16> for HTTPStatusCode {
+    type Error = ();
+
+    fn try_from(code: u16) -> Result<HTTPStatusCode, Self::Error> {
+        match code {
+            101 => Ok(HTTPStatusCode::SwitchingProtocols),
+            200 => Ok(HTTPStatusCode::OK),
+            201 => Ok(HTTPStatusCode::Created),
+            202 => Ok(HTTPStatusCode::Accepted),
+            204 => Ok(HTTPStatusCode::NoContent),
+            206 => Ok(HTTPStatusCode::PartialContent),
+            301 => Ok(HTTPStatusCode::MovedPermanently),
+            302 => Ok(HTTPStatusCode::Found),
+            304 => Ok(HTTPStatusCode::NotModified),
+            400 => Ok(HTTPStatusCode::BadRequest),
+            401 => Ok(HTTPStatusCode::Unauthorized),
+            403 => Ok(HTTPStatusCode::Forbidden),
+            404 => Ok(HTTPStatusCode::NotFound),
+            405 => Ok(HTTPStatusCode::MethodNotAllowed),
+            406 => Ok(HTTPStatusCode::NotAcceptable),
+            408 => Ok(HTTPStatusCode::RequestTimeout),
+            409 => Ok(HTTPStatusCode::Conflict),
+            410 => Ok(HTTPStatusCode::Gone),
+            411 => Ok(HTTPStatusCode::LengthRequired),
+            412 => Ok(HTTPStatusCode::PreconditionFailed),
+            413 => Ok(HTTPStatusCode::PayloadTooLarge),
+            414 => Ok(HTTPStatusCode::URITooLong),
+            415 => Ok(HTTPStatusCode::UnsupportedMediaType),
+            416 => Ok(HTTPStatusCode::RangeNotSatisfiable),
+            417 => Ok(HTTPStatusCode::ExpectationFailed),
+            429 => Ok(HTTPStatusCode::TooManyRequests),
+            431 => Ok(HTTPStatusCode::RequestHeaderFieldsTooLarge),
+            500 => Ok(HTTPStatusCode::InternalServerError),
+            501 => Ok(HTTPStatusCode::NotImplemented),
+            502 => Ok(HTTPStatusCode::BadGateway),
+            503 => Ok(HTTPStatusCode::ServiceUnavailable),
+            505 => Ok(HTTPStatusCode::HTTPVersionNotSupported),
+            _ => Err(()),
+        }
+    }
+}
+
+impl HTTPStatusCode {
+    /// The standard reason phrase sent after the code in a status line.
+    fn reason_phrase(&self) -> &'static str {
+        match self {
+            HTTPStatusCode::SwitchingProtocols => "Switching Protocols",
+            HTTPStatusCode::OK => "OK",
+            HTTPStatusCode::Created => "Created",
+            HTTPStatusCode::Accepted => "Accepted",
+            HTTPStatusCode::NoContent => "No Content",
+            HTTPStatusCode::PartialContent => "Partial Content",
+            HTTPStatusCode::MovedPermanently => "Moved Permanently",
+            HTTPStatusCode::Found => "Found",
+            HTTPStatusCode::NotModified => "Not Modified",
+            HTTPStatusCode::BadRequest => "Bad Request",
+            HTTPStatusCode::Unauthorized => "Unauthorized",
+            HTTPStatusCode::Forbidden => "Forbidden",
+            HTTPStatusCode::NotFound => "Not Found",
+            HTTPStatusCode::MethodNotAllowed => "Method Not Allowed",
+            HTTPStatusCode::NotAcceptable => "Not Acceptable",
+            HTTPStatusCode::RequestTimeout => "Request Timeout",
+            HTTPStatusCode::Conflict => "Conflict",
+            HTTPStatusCode::Gone => "Gone",
+            HTTPStatusCode::LengthRequired => "Length Required",
+            HTTPStatusCode::PreconditionFailed => "Precondition Failed",
+            HTTPStatusCode::PayloadTooLarge => "Payload Too Large",
+            HTTPStatusCode::URITooLong => "URI Too Long",
+            HTTPStatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            HTTPStatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            HTTPStatusCode::ExpectationFailed => "Expectation Failed",
+            HTTPStatusCode::TooManyRequests => "Too Many Requests",
+            HTTPStatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HTTPStatusCode::InternalServerError => "Internal Server Error",
+            HTTPStatusCode::NotImplemented => "Not Implemented",
+            HTTPStatusCode::BadGateway => "Bad Gateway",
+            HTTPStatusCode::ServiceUnavailable => "Service Unavailable",
+            HTTPStatusCode::HTTPVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
 }
 
 impl Display for HTTPStatusCode {
@@ -37,6 +173,7 @@ impl Display for HTTPStatusCode {
     }
 }
 
+#[derive(Clone, Copy)]
 enum HTTPVersion {
     V1_0,
     V1_1,
@@ -56,6 +193,19 @@ impl FromStr for HTTPVersion {
     }
 }
 
+impl Display for HTTPVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            HTTPVersion::V1_0 => "HTTP/1.0",
+            HTTPVersion::V1_1 => "HTTP/1.1",
+            HTTPVersion::V2_0 => "HTTP/2.0",
+        };
+        write!(f, "{}", version)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum HTTPMethod {
     GET,
     POST,
@@ -63,6 +213,7 @@ enum HTTPMethod {
     DELETE,
     HEAD,
     PATCH,
+    OPTIONS,
 }
 
 impl FromStr for HTTPMethod {
@@ -76,175 +227,2906 @@ impl FromStr for HTTPMethod {
             "DELETE" => Ok(HTTPMethod::DELETE),
             "HEAD" => Ok(HTTPMethod::HEAD),
             "PATCH" => Ok(HTTPMethod::PATCH),
+            "OPTIONS" => Ok(HTTPMethod::OPTIONS),
             _ => Err(()),
         }
     }
 }
 
+impl HTTPMethod {
+    /// Methods whose requests are expected to carry a body.
+    fn has_body(&self) -> bool {
+        matches!(self, HTTPMethod::POST | HTTPMethod::PUT | HTTPMethod::PATCH)
+    }
+}
+
+impl Display for HTTPMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let method = match self {
+            HTTPMethod::GET => "GET",
+            HTTPMethod::POST => "POST",
+            HTTPMethod::PUT => "PUT",
+            HTTPMethod::DELETE => "DELETE",
+            HTTPMethod::HEAD => "HEAD",
+            HTTPMethod::PATCH => "PATCH",
+            HTTPMethod::OPTIONS => "OPTIONS",
+        };
+        write!(f, "{}", method)
+    }
+}
+
+#[allow(dead_code)]
+struct HTTPRequest {
+    method: HTTPMethod,
+    /// The request target as sent, query string included, with any
+    /// absolute-form scheme and authority removed.
+    target: String,
+    path: String,
+    query: HashMap<String, String>,
+    version: HTTPVersion,
+    /// Keyed by lowercased name, since header names are case-insensitive.
+    /// A repeated header maps to all its values joined with `, ` (or `; `
+    /// for Cookie), which is equivalent for every list-valued header.
+    headers: HashMap<String, String>,
+    /// Every header line as received, with lowercased names, for the rare
+    /// header whose repeats can't be joined.
+    header_lines: Vec<(String, String)>,
+    /// The Host header's name, lowercased and without any port; always
+    /// present for HTTP/1.1 but may be missing for HTTP/1.0.
+    host: Option<String>,
+    body: Vec<u8>,
+}
+
+impl HTTPRequest {
+    /// Reads the next request off the connection, or `None` if the client
+    /// closed it before sending anything.
+    async fn parse<S: AsyncRead + AsyncWrite + Unpin>(
+        reader: &mut BufReader<S>,
+        config: &Config,
+    ) -> Result<Option<HTTPRequest>, RequestError> {
+        let timeout = config.read_timeout;
+        let limit = config.max_header_size;
+        // The deadline covers the whole line, so a client trickling bytes
+        // without ever finishing it still gets its 408.
+        let line = within(
+            timeout,
+            read_bounded_line(reader, limit, RequestError::URITooLong),
+        )
+        .await?;
+        // A client that connects and closes without sending anything gets
+        // no response, but one that stops partway through the request line
+        // has sent a bad request.
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(RequestError::Malformed);
+        }
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(RequestError::Malformed);
+        };
+        // A well-formed method we just don't know, TRACE say, is a 501.
+        let method = match method.parse::<HTTPMethod>() {
+            Ok(method) => method,
+            Err(_) if method.bytes().all(is_token_char) => {
+                return Err(RequestError::NotImplemented)
+            }
+            Err(_) => return Err(RequestError::Malformed),
+        };
+        let (authority, target) = split_absolute_form(target);
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), url::parse_query(query)),
+            None => (target.clone(), HashMap::new()),
+        };
+        let version = parts
+            .next()
+            .ok_or(RequestError::Malformed)?
+            .parse::<HTTPVersion>()
+            .map_err(|_| RequestError::VersionNotSupported)?;
+
+        let header_lines = within(
+            timeout,
+            read_headers(reader, limit - line.len(), config.max_headers),
+        )
+        .await?;
+        let mut headers = combine_headers(&header_lines);
+        // An absolute-form target's authority takes the place of Host.
+        if let Some(authority) = authority {
+            headers.insert("host".to_string(), authority);
+        }
+        // HTTP/1.1 requires exactly one Host; repeats arrive comma-joined.
+        let host = headers.get("host").map(|host| host_name(host));
+        match &host {
+            Some(host) if host.contains(',') => return Err(RequestError::Malformed),
+            None if !matches!(version, HTTPVersion::V1_0) => return Err(RequestError::Malformed),
+            _ => {}
+        }
+
+        // Any framed body is read whatever the method, even if no handler
+        // will look at it, so a keep-alive connection is left positioned at
+        // the start of the next request.
+        // HTTP/1.0 clients can't have meant an expectation (RFC 9110,
+        // section 10.1.1), so theirs are ignored.
+        let expects_continue = match headers.get("expect") {
+            _ if matches!(version, HTTPVersion::V1_0) => false,
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => true,
+            Some(_) => return Err(RequestError::ExpectationFailed),
+            None => false,
+        };
+        // Framing by both, or by disagreeing lengths, could be read
+        // differently by a proxy in front of us, which is how requests get
+        // smuggled past it.
+        if headers.contains_key("transfer-encoding") && headers.contains_key("content-length") {
+            return Err(RequestError::Malformed);
+        }
+        let mut body = Vec::new();
+        if is_chunked(&headers) {
+            if expects_continue {
+                send_continue(reader).await?;
+            }
+            body = within(timeout, read_chunked_body(reader, config.max_body_size)).await?;
+        } else if let Some(con_length) = headers.get("content-length") {
+            // Repeated headers arrive joined by commas; identical copies are
+            // tolerated.
+            let mut lengths = con_length.split(',').map(str::trim);
+            let con_length = lengths.next().unwrap_or_default();
+            if lengths.any(|length| length != con_length) {
+                return Err(RequestError::Malformed);
+            }
+            // `parse` alone would also accept a leading `+`.
+            if con_length.is_empty() || !con_length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(RequestError::Malformed);
+            }
+            let con_length = con_length
+                .parse::<usize>()
+                .map_err(|_| RequestError::Malformed)?;
+            // Refuse before allocating anything for the body.
+            if con_length > config.max_body_size {
+                return Err(if expects_continue {
+                    RequestError::ExpectationFailed
+                } else {
+                    RequestError::BodyTooLarge
+                });
+            }
+            if expects_continue && con_length > 0 {
+                send_continue(reader).await?;
+            }
+            body = within(
+                timeout,
+                read_sized_body(reader, con_length, config.read_buffer_size),
+            )
+            .await?;
+        } else if method.has_body() {
+            return Err(RequestError::LengthRequired);
+        }
+
+        Ok(Some(HTTPRequest {
+            method,
+            target,
+            path,
+            query,
+            version,
+            headers,
+            header_lines,
+            host,
+            body,
+        }))
+    }
+
+    /// HTTP/1.1 connections persist unless the client asks to close them,
+    /// HTTP/1.0 ones only when the client asks to keep them alive. An
+    /// explicit `close` wins in either version, even alongside `keep-alive`.
+    fn keep_alive(&self) -> bool {
+        if self.has_connection_option("close") {
+            return false;
+        }
+        match self.version {
+            HTTPVersion::V1_0 => self.has_connection_option("keep-alive"),
+            _ => true,
+        }
+    }
+
+    /// Every value of a possibly repeated header, in the order received.
+    fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.header_lines
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The request's cookies, from however many Cookie headers it has.
+    fn cookies(&self) -> HashMap<String, String> {
+        self.get_all("cookie").flat_map(cookie::parse).collect()
+    }
+
+    /// The fields of an `application/x-www-form-urlencoded` body, or none
+    /// when the body has another type.
+    fn form(&self) -> HashMap<String, String> {
+        let is_form = self
+            .headers
+            .get("content-type")
+            .is_some_and(|content_type| {
+                content_type
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            });
+        if is_form {
+            url::parse_form_urlencoded(&self.body)
+        } else {
+            HashMap::new()
+        }
+    }
+
+    fn has_connection_option(&self, option: &str) -> bool {
+        self.headers.get("connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        })
+    }
+}
+
+/// Tells a client that sent `Expect: 100-continue` to go ahead with the
+/// body it is holding back.
+async fn send_continue<S: AsyncWrite + Unpin>(writer: &mut S) -> io::Result<()> {
+    writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    writer.flush().await
+}
+
+/// Whether `byte` may appear in a token, such as a method name (RFC 9110,
+/// section 5.6.2).
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// The host part of a Host header, lowercased: `Example.com:8080` gives
+/// `example.com`, and `[::1]:80` gives `[::1]`.
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        Some(rest) => match rest.find(']') {
+            Some(end) => &host[..end + 2],
+            None => host,
+        },
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.to_ascii_lowercase()
+}
+
+/// Splits an absolute-form target such as `http://example.com/a?b` into
+/// its authority and the origin-form `/a?b`; any other target is returned
+/// as it is.
+fn split_absolute_form(target: &str) -> (Option<String>, String) {
+    match target.split_once("://") {
+        Some((scheme, rest))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (authority, rest) = rest.split_at(end);
+            let target = if rest.starts_with('/') {
+                rest.to_string()
+            } else {
+                format!("/{}", rest)
+            };
+            (Some(authority.to_string()), target)
+        }
+        _ => (None, target.to_string()),
+    }
+}
+
+/// Reads one line, failing with `too_long` if no line break turns up
+/// within `limit` bytes, or as malformed if it isn't UTF-8. An empty line
+/// means the client closed the connection.
+async fn read_bounded_line<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    limit: usize,
+    too_long: RequestError,
+) -> Result<String, RequestError> {
+    let mut line = String::new();
+    match reader.take(limit as u64).read_line(&mut line).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(RequestError::Malformed),
+        Err(e) => return Err(e.into()),
+    }
+    if line.len() == limit && !line.ends_with('\n') {
+        return Err(too_long);
+    }
+    Ok(line)
+}
+
+/// Reads header lines up to the blank line that ends them.
+async fn read_headers<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    mut limit: usize,
+    max_headers: usize,
+) -> Result<Vec<(String, String)>, RequestError> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_bounded_line(reader, limit, RequestError::HeadersTooLarge).await?;
+        limit -= line.len();
+        if line == "\r\n" {
+            return Ok(headers);
+        }
+        if headers.len() == max_headers {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        let header = line.split_once(':').ok_or(RequestError::Malformed)?;
+        headers.push((
+            header.0.trim().to_ascii_lowercase(),
+            header.1.trim().to_string(),
+        ));
+    }
+}
+
+/// Folds repeated headers into one value each, as RFC 7230 allows for
+/// list-valued ones.
+fn combine_headers(lines: &[(String, String)]) -> HashMap<String, String> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in lines {
+        match headers.get_mut(name) {
+            Some(combined) => {
+                // Cookie pairs are separated by `; `, not commas.
+                combined.push_str(if name == "cookie" { "; " } else { ", " });
+                combined.push_str(value);
+            }
+            None => {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    headers
+}
+
+/// Runs one step of reading a request, giving up with `Timeout` if the
+/// client hasn't delivered it within `limit`.
+async fn within<T, E: Into<RequestError>>(
+    limit: Duration,
+    read: impl Future<Output = Result<T, E>>,
+) -> Result<T, RequestError> {
+    match time::timeout(limit, read).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(RequestError::Timeout),
+    }
+}
+
+fn is_chunked(headers: &HashMap<String, String>) -> bool {
+    headers.get("transfer-encoding").is_some_and(|encodings| {
+        encodings
+            .split(',')
+            .any(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"))
+    })
+}
+
+/// Reads a `Content-Length` body at most `chunk_size` bytes at a time, so
+/// a client declaring a large body but sending little can't make us
+/// allocate for all of it up front. The body is still gathered in memory
+/// for the handler, so `--max-body-size` is what bounds its size.
+async fn read_sized_body<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    len: usize,
+    chunk_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut chunk = vec![0; chunk_size.min(len)];
+    while body.len() < len {
+        let wanted = chunk.len().min(len - body.len());
+        let read = reader.read(&mut chunk[..wanted]).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    Ok(body)
+}
+
+/// Reassembles a `Transfer-Encoding: chunked` body: each chunk is a hex
+/// size line followed by that many bytes and a CRLF, up to a zero-sized
+/// chunk and an optional block of trailer headers, which are discarded.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    max_body_size: usize,
+) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_bounded_line(reader, MAX_CHUNK_HEADER, RequestError::Malformed).await?;
+        if line.is_empty() {
+            return Err(RequestError::Malformed);
+        }
+        // Chunk extensions after `;` carry nothing we use.
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::Malformed)?;
+        if size == 0 {
+            break;
+        }
+        // Written so a huge size can't overflow the sum.
+        if size > max_body_size - body.len() {
+            return Err(RequestError::BodyTooLarge);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(RequestError::Malformed);
+        }
+    }
+
+    let mut limit = MAX_CHUNK_HEADER;
+    loop {
+        let trailer = read_bounded_line(reader, limit, RequestError::Malformed).await?;
+        if trailer.is_empty() {
+            return Err(RequestError::Malformed);
+        }
+        if trailer == "\r\n" {
+            return Ok(body);
+        }
+        limit -= trailer.len();
+    }
+}
+
+/// Bytes allowed for a chunk-size line, and for the trailer block as a
+/// whole.
+const MAX_CHUNK_HEADER: usize = 8 * 1024;
+
+/// Why the next request could not be read off a connection.
+enum RequestError {
+    /// The request line or headers are not valid HTTP.
+    Malformed,
+    /// The declared body is larger than `--max-body-size`.
+    BodyTooLarge,
+    /// The request line alone is longer than `--max-header-size`.
+    URITooLong,
+    /// The request line and headers together are longer than
+    /// `--max-header-size`, or there are more than `--max-headers` of them.
+    HeadersTooLarge,
+    /// A method that carries a body arrived without any way to frame it.
+    LengthRequired,
+    /// The request line names an HTTP version we don't speak.
+    VersionNotSupported,
+    /// The method is none of those `HTTPMethod` knows.
+    NotImplemented,
+    /// An `Expect` we can't meet: anything but `100-continue`, or a body
+    /// that would be refused anyway.
+    ExpectationFailed,
+    /// Part of the request didn't arrive within `--read-timeout`.
+    Timeout,
+    Io(io::Error),
+}
+
+impl RequestError {
+    fn response(&self) -> HTTPResponse {
+        let code = match self {
+            RequestError::BodyTooLarge => HTTPStatusCode::PayloadTooLarge,
+            RequestError::LengthRequired => HTTPStatusCode::LengthRequired,
+            RequestError::URITooLong => HTTPStatusCode::URITooLong,
+            RequestError::HeadersTooLarge => HTTPStatusCode::RequestHeaderFieldsTooLarge,
+            RequestError::VersionNotSupported => HTTPStatusCode::HTTPVersionNotSupported,
+            RequestError::ExpectationFailed => HTTPStatusCode::ExpectationFailed,
+            RequestError::Timeout => HTTPStatusCode::RequestTimeout,
+            RequestError::NotImplemented => HTTPStatusCode::NotImplemented,
+            // Includes a client closing the connection before sending the
+            // full body it announced in Content-Length.
+            RequestError::Malformed | RequestError::Io(_) => HTTPStatusCode::BadRequest,
+        };
+        HTTPResponse::new(code).with_header("Connection", "close")
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+/// Files are streamed to the client in chunks of this size rather than
+/// being read into memory up front.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+enum Body {
+    Bytes(Vec<u8>),
+    /// Streams the given number of bytes from the file's current position.
+    File(File, u64),
+    /// Streams the given number of bytes from a reader, such as an
+    /// upstream connection.
+    Stream(Box<dyn AsyncRead + Send + Unpin>, u64),
+    /// Streams a reader of unknown length to its end, as chunks.
+    Chunked(Box<dyn AsyncRead + Send + Unpin>),
+    /// Streams each range of the file after its part header, then the
+    /// closing delimiter, for `multipart/byteranges`.
+    Parts(File, Vec<(Vec<u8>, ByteRange)>, Vec<u8>),
+}
+
 struct HTTPResponse {
     code: HTTPStatusCode,
+    /// Sent in place of `code` for a relayed upstream status that
+    /// `HTTPStatusCode` has no variant for; `code` is then the generic
+    /// status of the same class.
+    relayed: Option<u16>,
     message: String,
     headers: Option<Vec<String>>,
-    body: Option<String>,
+    body: Option<Body>,
 }
 
 impl HTTPResponse {
-    fn format(&self) -> String {
+    /// A response with the code's standard reason phrase.
+    fn new(code: HTTPStatusCode) -> HTTPResponse {
+        HTTPResponse {
+            code,
+            relayed: None,
+            message: code.reason_phrase().to_string(),
+            headers: None,
+            body: None,
+        }
+    }
+
+    fn ok() -> HTTPResponse {
+        HTTPResponse::new(HTTPStatusCode::OK)
+    }
+
+    /// A response with whatever final status an upstream sent, and its
+    /// reason phrase, or `None` for a number that isn't one.
+    fn relayed(status: u16, message: &str) -> Option<HTTPResponse> {
+        let mut response = match HTTPStatusCode::try_from(status) {
+            Ok(code) => HTTPResponse::new(code),
+            Err(()) => {
+                let code = match status {
+                    200..=299 => HTTPStatusCode::OK,
+                    300..=399 => HTTPStatusCode::Found,
+                    400..=499 => HTTPStatusCode::BadRequest,
+                    500..=599 => HTTPStatusCode::InternalServerError,
+                    _ => return None,
+                };
+                let mut response = HTTPResponse::new(code);
+                response.relayed = Some(status);
+                response
+            }
+        };
+        response.message = message.to_string();
+        Some(response)
+    }
+
+    /// The number sent in the status line.
+    fn status(&self) -> u16 {
+        self.relayed.unwrap_or(self.code as u16)
+    }
+
+    /// Appends a header; headers are sent in the order they were added.
+    fn with_header(mut self, name: &str, value: impl Display) -> HTTPResponse {
+        self.headers
+            .get_or_insert_with(Vec::new)
+            .push(format!("{}: {}", name, value));
+        self
+    }
+
+    /// Adds `name` to the Vary header, creating it if need be, to tell
+    /// caches the response depends on that request header.
+    fn with_vary(mut self, name: &str) -> HTTPResponse {
+        let existing = self.headers.iter_mut().flatten().find(|header| {
+            header
+                .split_once(':')
+                .is_some_and(|(header, _)| header.eq_ignore_ascii_case("Vary"))
+        });
+        match existing {
+            Some(vary) => {
+                let (_, names) = vary.split_once(':').unwrap_or_default();
+                let listed = names
+                    .split(',')
+                    .any(|listed| listed.trim().eq_ignore_ascii_case(name));
+                if !listed {
+                    vary.push_str(&format!(", {}", name));
+                }
+                self
+            }
+            None => self.with_header("Vary", name),
+        }
+    }
+
+    /// The value of the first header called `name`.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().flatten().find_map(|header| {
+            let (header, value) = header.split_once(':')?;
+            header
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim_start())
+        })
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.header(name).is_some()
+    }
+
+    fn with_content_type(self, content_type: &str) -> HTTPResponse {
+        self.with_header("Content-Type", content_type)
+    }
+
+    /// Attaches an in-memory body along with its Content-Length.
+    fn with_body(self, body: Vec<u8>) -> HTTPResponse {
+        let mut response = self.with_header("Content-Length", body.len());
+        response.body = Some(Body::Bytes(body));
+        response
+    }
+
+    /// Attaches `len` bytes of `file`, from its current position, to be
+    /// streamed, along with their Content-Length.
+    fn with_file(self, file: File, len: u64) -> HTTPResponse {
+        let mut response = self.with_header("Content-Length", len);
+        response.body = Some(Body::File(file, len));
+        response
+    }
+
+    /// Attaches `len` bytes of `stream` to be relayed, along with their
+    /// Content-Length.
+    fn with_stream(self, stream: Box<dyn AsyncRead + Send + Unpin>, len: u64) -> HTTPResponse {
+        let mut response = self.with_header("Content-Length", len);
+        response.body = Some(Body::Stream(stream, len));
+        response
+    }
+
+    /// Attaches the ranges of `file` that `parts` pair with their headers,
+    /// followed by `tail`, along with their total Content-Length.
+    fn with_parts(
+        self,
+        file: File,
+        parts: Vec<(Vec<u8>, ByteRange)>,
+        tail: Vec<u8>,
+    ) -> HTTPResponse {
+        let len: u64 = parts
+            .iter()
+            .map(|(head, range)| head.len() as u64 + range.len())
+            .sum::<u64>()
+            + tail.len() as u64;
+        let mut response = self.with_header("Content-Length", len);
+        response.body = Some(Body::Parts(file, parts, tail));
+        response
+    }
+
+    /// Attaches `stream`, whose length isn't known up front, to be relayed
+    /// with `Transfer-Encoding: chunked`.
+    fn with_chunked(self, stream: Box<dyn AsyncRead + Send + Unpin>) -> HTTPResponse {
+        let mut response = self.with_header("Transfer-Encoding", "chunked");
+        response.body = Some(Body::Chunked(stream));
+        response
+    }
+
+    fn is_chunked(&self) -> bool {
+        matches!(self.body, Some(Body::Chunked(_)))
+    }
+
+    /// For `--error-pages`: gives an error status without a body the
+    /// contents of `<directory>/<status>.html`, if there is such a file.
+    fn with_error_page(self, directory: &Path) -> HTTPResponse {
+        if self.status() < 400 || self.body.is_some() {
+            return self;
+        }
+        match std::fs::read(directory.join(format!("{}.html", self.status()))) {
+            Ok(page) => self.with_content_type("text/html").with_body(page),
+            Err(_) => self,
+        }
+    }
+
+    /// For `--json-errors`: gives an error status without a body one like
+    /// `{"error":"Not Found","status":404}`.
+    fn with_json_error(self) -> HTTPResponse {
+        if self.status() < 400 || self.body.is_some() {
+            return self;
+        }
+        let message = self.message.replace('\\', "\\\\").replace('"', "\\\"");
+        let json = format!("{{\"error\":\"{}\",\"status\":{}}}", message, self.status());
+        self.with_content_type("application/json")
+            .with_body(json.into_bytes())
+    }
+
+    /// Serialises the status line, headers and any in-memory body. A file
+    /// body is left for `write_to` to stream. A Date header is added unless
+    /// one was set already, and a bodiless response that could otherwise
+    /// carry one gets `Content-Length: 0` so a client reading pipelined
+    /// responses knows where it ends.
+    fn format(&self, version: HTTPVersion) -> Vec<u8> {
         let mut headers = String::new();
+        if !self.has_header("Date") {
+            headers.push_str(&format!("Date: {}\r\n", DateTime::now().to_http_date()));
+        }
+        let code = self.status();
+        let bodiless = code < 200 || code == 204 || code == 304;
+        if !bodiless
+            && self.body.is_none()
+            && !self.has_header("Content-Length")
+            && !self.has_header("Transfer-Encoding")
+        {
+            headers.push_str("Content-Length: 0\r\n");
+        }
+        // HTTP/1.0 has no chunked coding; the body just runs to the close.
+        let unchunked = self.is_chunked() && matches!(version, HTTPVersion::V1_0);
         if let Some(headers_vec) = &self.headers {
             for header in headers_vec {
+                if unchunked && header.starts_with("Transfer-Encoding:") {
+                    continue;
+                }
                 headers.push_str(header);
                 headers.push_str("\r\n");
             }
         }
-        let body = if self.body.is_some() {
-            self.body.as_ref().unwrap()
-        } else {
-            ""
-        };
-        format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n{}",
-            self.code, self.message, headers, body
+        let mut response = format!(
+            "{} {} {}\r\n{}\r\n",
+            version,
+            self.status(),
+            self.message,
+            headers
         )
+        .into_bytes();
+        if let Some(Body::Bytes(body)) = &self.body {
+            response.extend_from_slice(body);
+        }
+        response
+    }
+
+    /// Writes the whole response with a status line for `version`,
+    /// returning how many bytes were sent.
+    async fn write_to<W: AsyncWrite + Unpin>(
+        self,
+        version: HTTPVersion,
+        writer: &mut W,
+    ) -> io::Result<u64> {
+        let head = self.format(version);
+        writer.write_all(&head).await?;
+        let mut written = head.len() as u64;
+        match self.body {
+            Some(Body::File(file, len)) => written += copy_exact(file, writer, len).await?,
+            Some(Body::Stream(stream, len)) => written += copy_exact(stream, writer, len).await?,
+            Some(Body::Parts(mut file, parts, tail)) => {
+                for (head, range) in parts {
+                    writer.write_all(&head).await?;
+                    file.seek(SeekFrom::Start(range.start)).await?;
+                    written +=
+                        head.len() as u64 + copy_exact(&mut file, writer, range.len()).await?;
+                }
+                writer.write_all(&tail).await?;
+                written += tail.len() as u64;
+            }
+            Some(Body::Chunked(stream)) if matches!(version, HTTPVersion::V1_0) => {
+                let mut stream = BufReader::with_capacity(FILE_CHUNK_SIZE, stream);
+                written += io::copy_buf(&mut stream, writer).await?;
+            }
+            Some(Body::Chunked(mut stream)) => {
+                let mut chunk = vec![0; FILE_CHUNK_SIZE];
+                loop {
+                    let read = stream.read(&mut chunk).await?;
+                    let size = format!("{:x}\r\n", read);
+                    writer.write_all(size.as_bytes()).await?;
+                    writer.write_all(&chunk[..read]).await?;
+                    writer.write_all(b"\r\n").await?;
+                    written += (size.len() + read + 2) as u64;
+                    // The zero-sized chunk, sent at the end, is the last.
+                    if read == 0 {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+        // TLS streams hold encrypted records back until flushed.
+        writer.flush().await?;
+        Ok(written)
     }
 }
 
-async fn handle_connection(
-    reader: &mut BufReader<&mut TcpStream>,
-    directory: &String,
-) -> io::Result<()> {
-    let mut line = String::new();
-    reader.read_line(&mut line).await.unwrap();
+/// Copies exactly `len` bytes from `reader`, as announced by the
+/// Content-Length already sent. Running out early is an `UnexpectedEof`
+/// error, so the connection closes instead of the client mis-framing
+/// whatever follows.
+async fn copy_exact<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: R,
+    writer: &mut W,
+    len: u64,
+) -> io::Result<u64> {
+    let mut reader = BufReader::with_capacity(FILE_CHUNK_SIZE, reader.take(len));
+    let copied = io::copy_buf(&mut reader, writer).await?;
+    if copied < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(copied)
+}
 
-    let path = line.split_whitespace().nth(1).unwrap();
-    let request = line.split_whitespace().nth(0).unwrap();
-    let mut headers = HashMap::new();
+/// Serves requests off one connection, plain or TLS, until either side
+/// closes it.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: &mut BufReader<S>,
+    peer: Peer,
+    config: &Config,
+    router: &Router,
+    metrics: &Metrics,
+    limiter: Option<&RateLimiter>,
+) -> io::Result<()> {
+    let mut served = 0;
     loop {
-        let mut line = String::new();
-        reader.read_line(&mut line).await.unwrap();
-        if line == "\r\n" {
-            break;
+        // An idle persistent connection is dropped quietly; there is no
+        // request to answer yet.
+        if served > 0
+            && time::timeout(config.keepalive_timeout, reader.fill_buf())
+                .await
+                .is_err()
+        {
+            return Ok(());
         }
-        let header = line.split_once(":").unwrap();
-        headers.insert(header.0.trim().to_string(), header.1.trim().to_string());
-    }
-
-    let response = match path.split("/").nth(1).unwrap() {
-        "echo" => {
-            let content = path.get(6..).unwrap();
-            let mut headers = Vec::new();
-            headers.push("Content-Type: text/plain".to_string());
-            headers.push(format!("Content-Length: {}", content.len()));
-            HTTPResponse {
-                code: HTTPStatusCode::OK,
-                message: "OK".to_string(),
-                headers: Some(headers),
-                body: Some(content.to_string()),
-            }
-        }
-        "user-agent" => {
-            let useragent = headers.get("User-Agent").unwrap();
-            let mut headers = Vec::new();
-            headers.push("Content-Type: text/plain".to_string());
-            headers.push(format!("Content-Length: {}", useragent.len()));
-            HTTPResponse {
-                code: HTTPStatusCode::OK,
-                message: "OK".to_string(),
-                headers: Some(headers),
-                body: Some(useragent.to_string()),
-            }
-        }
-        "files" => match request {
-            "GET" => {
-                let content =
-                    fs::read_to_string(format!("{}/{}", directory, path.get(7..).unwrap()));
-                if content.is_err() {
-                    HTTPResponse {
-                        code: HTTPStatusCode::NotFound,
-                        message: "Not Found".to_string(),
-                        headers: None,
-                        body: None,
-                    }
-                } else {
-                    let content = content.unwrap();
-                    let mut headers = Vec::new();
-                    headers.push("Content-Type: application/octet-stream".to_string());
-                    headers.push(format!("Content-Length: {}", content.len()));
-                    HTTPResponse {
-                        code: HTTPStatusCode::OK,
-                        message: "OK".to_string(),
-                        headers: Some(headers),
-                        body: Some(content.to_string()),
-                    }
-                }
+        let request = match HTTPRequest::parse(reader, config).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(RequestError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e),
+            // Whatever is left of a refused request can't be relied on to
+            // frame the next one, so answer and close. Its version isn't
+            // known for sure, so the response is sent as HTTP/1.1.
+            Err(error) => {
+                let response = with_configured_headers(error.response(), config);
+                let head = response.format(HTTPVersion::V1_1);
+                reader.write_all(&head).await?;
+                metrics.record(response.status(), head.len() as u64);
+                // Closing with unread input makes the kernel reset the
+                // connection, which can destroy the response before the
+                // client reads it, so briefly discard what is still coming.
+                reader.shutdown().await?;
+                let _ = time::timeout(LINGER_TIMEOUT, io::copy(reader, &mut io::sink())).await;
+                return Ok(());
             }
-            "POST" => {
-                let con_length = headers
-                    .get("Content-Length")
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap();
-                let mut body = vec![0; con_length];
-                reader.read(&mut body).await?;
-                fs::write(
-                    format!("{}/{}", directory, path.get(7..).unwrap()),
-                    body.as_bytes(),
-                )
-                .unwrap();
-                HTTPResponse {
-                    code: HTTPStatusCode::Created,
-                    message: "Created".to_string(),
-                    headers: None,
-                    body: None,
+        };
+
+        let started = Instant::now();
+        served += 1;
+        let request_id = match request.headers.get("x-request-id") {
+            Some(id) => id.clone(),
+            None => request_id::generate(),
+        };
+        let mut keep_alive = request.keep_alive() && served < config.max_requests_per_conn;
+        let mut response = if let Some(response) = intercept(&request, peer, config, limiter) {
+            response
+        } else if let Some(upstream) = config
+            .proxy_pass
+            .as_ref()
+            // During `--maintenance` the monitoring endpoints report on
+            // this server, not the upstream.
+            .filter(|_| !(config.maintenance && is_monitoring(&request)))
+        {
+            proxy::forward(&request, peer, &request_id, upstream, config.read_timeout).await
+        } else {
+            // A panicking handler costs its own request, answered with a
+            // 500, rather than the whole connection task.
+            let routed = panic::catch_unwind(AssertUnwindSafe(|| match &config.serve_file {
+                // `--serve-file` answers every GET the same way.
+                Some(file) if matches!(request.method, HTTPMethod::GET | HTTPMethod::HEAD) => {
+                    handlers::single_file(&request, config, file)
                 }
-            }
-            _ => HTTPResponse {
-                code: HTTPStatusCode::BadRequest,
-                message: "Bad Request".to_string(),
-                headers: None,
-                body: None,
-            },
-        },
-        "" => HTTPResponse {
-            code: HTTPStatusCode::OK,
-            message: "OK".to_string(),
-            headers: None,
-            body: None,
-        },
-        _ => HTTPResponse {
-            code: HTTPStatusCode::NotFound,
-            message: "Not Found".to_string(),
-            headers: None,
-            body: None,
-        },
-    };
-    reader
-        .write_all(response.format().as_bytes())
-        .await
-        .unwrap();
+                _ => router.route(&request, config),
+            }));
+            routed.unwrap_or_else(|_| {
+                log::error(format_args!(
+                    "{}: handler for {} {} panicked",
+                    peer, request.method, request.path
+                ));
+                keep_alive = false;
+                HTTPResponse::new(HTTPStatusCode::InternalServerError)
+            })
+        };
+        response = with_configured_headers(response, config);
+        if let Some(origin) = &config.cors_origin {
+            response = with_cors(response, &request, origin, router);
+        }
+        // HEAD gets exactly the headers GET would, Content-Length included,
+        // just without the body.
+        if request.method == HTTPMethod::HEAD {
+            response.body = None;
+        }
+        // A proxied response already carries the ID, passed upstream.
+        if !response.has_header("X-Request-Id") {
+            response = response.with_header("X-Request-Id", &request_id);
+        }
+        // Without chunking, only closing the connection can end the body.
+        if response.is_chunked() && matches!(request.version, HTTPVersion::V1_0) {
+            keep_alive = false;
+        }
+        // Said explicitly whatever the version, so an HTTP/1.0 client that
+        // didn't ask for keep-alive sees the close it gets.
+        if !response.has_header("Connection") {
+            response = response.with_header(
+                "Connection",
+                if keep_alive { "keep-alive" } else { "close" },
+            );
+        }
+        let (code, status) = (response.code, response.status());
+        let written = response.write_to(request.version, reader).await?;
+        metrics.record(status, written);
+        if !config.quiet {
+            log::access(
+                peer,
+                &request,
+                status,
+                written,
+                started.elapsed(),
+                &request_id,
+                config.log_format,
+            );
+        }
+
+        if let HTTPStatusCode::SwitchingProtocols = code {
+            // No WebSocket frames are interpreted yet; the connection is
+            // just held open until the client closes it.
+            io::copy(reader, &mut io::sink()).await?;
+            return Ok(());
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
 
-    Ok(())
+/// The endpoints load balancers and scrapers poll, which stay up during
+/// `--maintenance`.
+fn is_monitoring(request: &HTTPRequest) -> bool {
+    matches!(request.path.as_str(), "/health" | "/metrics")
 }
 
-#[tokio::main]
-async fn main() {
-    let mut dir = String::new();
-    for argument in env::args() {
-        if dir == "--directory" {
-            dir = argument;
-            break;
+/// Answers requests that never reach a handler: all but the monitoring
+/// endpoints during `--maintenance`, those over the rate limit, those
+/// lacking credentials, and WebSocket upgrades.
+fn intercept(
+    request: &HTTPRequest,
+    peer: Peer,
+    config: &Config,
+    limiter: Option<&RateLimiter>,
+) -> Option<HTTPResponse> {
+    if config.maintenance && !is_monitoring(request) {
+        return Some(
+            HTTPResponse::new(HTTPStatusCode::ServiceUnavailable)
+                .with_header("Retry-After", MAINTENANCE_RETRY_AFTER.as_secs()),
+        );
+    }
+    // Unix-socket peers can't be told apart, so aren't limited.
+    if let Some(Err(wait)) = limiter
+        .zip(peer.ip())
+        .map(|(limiter, ip)| limiter.check(ip))
+    {
+        // Retry-After is in whole seconds, so round up.
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return Some(
+            HTTPResponse::new(HTTPStatusCode::TooManyRequests).with_header("Retry-After", seconds),
+        );
+    }
+    if !auth::authorized(request, config) {
+        return Some(auth::challenge(config));
+    }
+    if websocket::is_upgrade(request) {
+        return Some(websocket::handshake(request));
+    }
+    None
+}
+
+/// Applies `--error-pages` and `--json-errors`, and adds the `--header`
+/// headers and Server, leaving out any the response already sets itself.
+fn with_configured_headers(mut response: HTTPResponse, config: &Config) -> HTTPResponse {
+    if let Some(directory) = &config.error_pages {
+        response = response.with_error_page(directory);
+    }
+    if config.json_errors {
+        response = response.with_json_error();
+    }
+    for (name, value) in &config.headers {
+        if !response.has_header(name) {
+            response = response.with_header(name, value);
+        }
+    }
+    if let Some(token) = &config.server_token {
+        if !response.has_header("Server") {
+            response = response.with_header("Server", token);
         }
-        dir = argument;
     }
-    let listener = TcpListener::bind("127.0.0.1:4221").await.unwrap();
+    response
+}
 
-    loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
-        let dir = dir.clone();
+/// Adds the `--cors-origin` headers, plus the preflight ones when
+/// answering OPTIONS.
+fn with_cors(
+    response: HTTPResponse,
+    request: &HTTPRequest,
+    origin: &str,
+    router: &Router,
+) -> HTTPResponse {
+    let response = response.with_header("Access-Control-Allow-Origin", origin);
+    if request.method != HTTPMethod::OPTIONS {
+        return response;
+    }
+    let methods: Vec<String> = router
+        .allowed_methods(&request.path)
+        .iter()
+        .map(|method| method.to_string())
+        .collect();
+    // Whatever headers the preflight asks about are fine by us.
+    let headers = request
+        .headers
+        .get("access-control-request-headers")
+        .map_or("Content-Type", |headers| headers.as_str());
+    response
+        .with_header("Access-Control-Allow-Methods", methods.join(", "))
+        .with_header("Access-Control-Allow-Headers", headers)
+}
 
-        tokio::spawn(async move {
-            let mut reader: BufReader<&mut TcpStream> = BufReader::new(&mut socket);
-            handle_connection(&mut reader, &dir).await.unwrap();
-        });
+/// Runs `handle_connection` over any transport, buffering reads.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: Peer,
+    config: &Config,
+    router: &Router,
+    metrics: &Metrics,
+    limiter: Option<&RateLimiter>,
+) {
+    let _open = metrics.connection();
+    let mut reader = BufReader::new(stream);
+    let served = handle_connection(&mut reader, peer, config, router, metrics, limiter).await;
+    let served = match served {
+        // Closing properly sends TLS clients the close_notify that tells
+        // them nothing was cut off.
+        Ok(()) => reader.shutdown().await,
+        Err(e) => Err(e),
+    };
+    match served {
+        Ok(()) => {}
+        // A client going away mid-response is routine, not a server fault.
+        Err(e) if is_disconnect(&e) => {
+            log::debug(format_args!("connection {}: client went away: {}", peer, e))
+        }
+        Err(e) => log::error(format_args!("connection {}: {}", peer, e)),
+    }
+}
+
+/// Completes the TLS handshake, then serves the connection. The handshake
+/// gets `--read-timeout` like any other read, so a client that never sends
+/// its ClientHello can't hold a connection slot.
+async fn serve_tls<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+    peer: Peer,
+    config: &Config,
+    router: &Router,
+    metrics: &Metrics,
+    limiter: Option<&RateLimiter>,
+) {
+    match time::timeout(config.read_timeout, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => serve(stream, peer, config, router, metrics, limiter).await,
+        Ok(Err(e)) => log::warn(format_args!("tls handshake with {} failed: {}", peer, e)),
+        Err(_) => log::warn(format_args!("tls handshake with {} timed out", peer)),
+    }
+}
+
+fn is_disconnect(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+    )
+}
+
+/// How long a refused request's remaining input is drained before the
+/// connection is closed.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long clients are asked to wait during `--maintenance`.
+const MAINTENANCE_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long open connections may keep running after a shutdown signal.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    };
+    log::set_level(config.log_level);
+    let listener = match Listener::bind(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let address = match &config.unix_socket {
+                Some(path) => path.display().to_string(),
+                None => config.address().to_string(),
+            };
+            log::error(format_args!("could not bind {}: {}", address, e));
+            process::exit(1);
+        }
+    };
+
+    let acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                log::error(format_args!("{}", e));
+                process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    run(
+        listener,
+        Arc::new(config),
+        acceptor,
+        &METRICS,
+        shutdown_signal(),
+    )
+    .await;
+}
+
+/// Accepts connections until `shutdown` resolves, or after the first one
+/// with `--once`, then gives those still in flight a grace period. Traffic
+/// is counted in `metrics`, which outside of tests is the `METRICS` that
+/// `/metrics` serves.
+async fn run(
+    listener: Listener,
+    config: Arc<Config>,
+    acceptor: Option<TlsAcceptor>,
+    metrics: &'static Metrics,
+    shutdown: impl Future<Output = ()>,
+) {
+    let router = Arc::new(handlers::router());
+    let limiter = config
+        .rate_limit
+        .map(|rate| Arc::new(RateLimiter::new(rate)));
+    let permits = Arc::new(Semaphore::new(
+        config.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+    ));
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            // Only accept once a permit is free, so connections over the
+            // limit queue in the listen backlog.
+            (permit, accepted) = async {
+                let permit = Arc::clone(&permits).acquire_owned().await.unwrap();
+                (permit, listener.accept().await)
+            } => {
+                let (socket, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error(format_args!("accept failed: {}", e));
+                        continue;
+                    }
+                };
+                log::debug(format_args!("accepted connection from {}", peer));
+                let once = config.once;
+                let config = Arc::clone(&config);
+                let router = Arc::clone(&router);
+                let limiter = limiter.clone();
+                let acceptor = acceptor.clone();
+
+                connections.spawn(async move {
+                    let _permit = permit;
+                    let limiter = limiter.as_deref();
+                    match acceptor {
+                        Some(acceptor) => {
+                            serve_tls(&acceptor, socket, peer, &config, &router, metrics, limiter)
+                                .await
+                        }
+                        None => serve(socket, peer, &config, &router, metrics, limiter).await,
+                    }
+                });
+                // The one connection is still drained below like any other.
+                if once {
+                    break;
+                }
+            }
+            // Reap finished connections so the set only holds live ones.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting, then give in-flight connections a bounded window to
+    // finish before the remaining ones are aborted.
+    drop(listener);
+    let drained = time::timeout(SHUTDOWN_GRACE_PERIOD, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log::warn(format_args!(
+            "shutdown: aborting {} connection(s) still open after {:?}",
+            connections.len(),
+            SHUTDOWN_GRACE_PERIOD
+        ));
+        connections.shutdown().await;
+    }
+    log::info(format_args!("shutdown: {}", metrics.summary()));
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where that exists.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("failed to listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use tokio::io::DuplexStream;
+
+    /// The address every in-memory client appears to connect from.
+    pub(crate) fn peer() -> Peer {
+        Peer::Tcp(SocketAddr::from(([127, 0, 0, 1], 50000)))
+    }
+
+    /// The defaults, minus the access log, serving `directory`.
+    pub(crate) fn config_for(directory: &Path) -> Config {
+        Config {
+            directory: directory.to_string_lossy().into_owned(),
+            quiet: true,
+            ..Config::default()
+        }
+    }
+
+    /// A fresh, empty directory for one test's files.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("http-server-rust-{}", process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs `handle_connection` against the server end of an in-memory
+    /// duplex while `client` drives the other end, returning whatever the
+    /// client produces once both are done.
+    pub(crate) async fn converse<F, Fut>(config: &Config, client: F) -> Fut::Output
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future,
+    {
+        converse_routed(config, &handlers::router(), client).await
+    }
+
+    /// `converse`, but with `router` in place of the built-in routes.
+    async fn converse_routed<F, Fut>(config: &Config, router: &Router, client: F) -> Fut::Output
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future,
+    {
+        let (client_end, server_end) = io::duplex(1 << 20);
+        let server = async move {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(&mut reader, peer(), config, router, &Metrics::new(), None).await
+        };
+        let (served, output) = tokio::join!(server, client(client_end));
+        served.expect("connection failed");
+        output
+    }
+
+    /// Sends `input` and closes the client's side, returning everything
+    /// the server wrote before it closed its own.
+    pub(crate) async fn exchange(config: &Config, input: &[u8]) -> Vec<u8> {
+        converse(config, |mut client| async move {
+            client.write_all(input).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await
+    }
+
+    /// A response as a client would read it off the wire.
+    #[derive(Debug)]
+    pub(crate) struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Response {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn text(&self) -> String {
+            String::from_utf8_lossy(&self.body).into_owned()
+        }
+    }
+
+    /// Splits `output` into the responses it holds, framing each body by
+    /// its Content-Length or chunked coding, or else by the end of the
+    /// output.
+    pub(crate) fn responses(mut output: &[u8]) -> Vec<Response> {
+        let mut responses = Vec::new();
+        while !output.is_empty() {
+            let end = find(output, b"\r\n\r\n").expect("unterminated response head");
+            let head = std::str::from_utf8(&output[..end]).unwrap();
+            output = &output[end + 4..];
+            let mut lines = head.split("\r\n");
+            let mut status_line = lines.next().unwrap().splitn(3, ' ');
+            assert!(status_line.next().unwrap().starts_with("HTTP/"));
+            let status: u16 = status_line.next().unwrap().parse().unwrap();
+            let headers: Vec<(String, String)> = lines
+                .map(|line| {
+                    let (name, value) = line.split_once(':').unwrap();
+                    (name.to_string(), value.trim().to_string())
+                })
+                .collect();
+            let mut response = Response {
+                status,
+                headers,
+                body: Vec::new(),
+            };
+            if status < 200 || status == 204 || status == 304 {
+                responses.push(response);
+                continue;
+            }
+            if response.header("Transfer-Encoding") == Some("chunked") {
+                loop {
+                    let end = find(output, b"\r\n").unwrap();
+                    let size = std::str::from_utf8(&output[..end]).unwrap();
+                    let size = usize::from_str_radix(size, 16).unwrap();
+                    output = &output[end + 2..];
+                    response.body.extend_from_slice(&output[..size]);
+                    output = &output[size + 2..];
+                    if size == 0 {
+                        break;
+                    }
+                }
+            } else if let Some(length) = response.header("Content-Length") {
+                let length: usize = length.parse().unwrap();
+                response.body = output[..length].to_vec();
+                output = &output[length..];
+            } else {
+                response.body = output.to_vec();
+                output = &[];
+            }
+            responses.push(response);
+        }
+        responses
+    }
+
+    /// The one response `output` should hold.
+    pub(crate) fn response(output: &[u8]) -> Response {
+        let mut responses = responses(output);
+        assert_eq!(responses.len(), 1, "expected a single response");
+        responses.remove(0)
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn serves_a_request_over_an_in_memory_duplex() {
+        let config = config_for(Path::new("."));
+        let output = exchange(&config, b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.contains("\r\nContent-Type: text/plain\r\n"));
+        assert!(output.contains("\r\nContent-Length: 3\r\n"));
+        assert!(output.ends_with("\r\n\r\nabc"));
+    }
+
+    #[tokio::test]
+    async fn chunked_uploads_are_reassembled() {
+        let dir = scratch_dir("chunked-upload");
+        let config = config_for(&dir);
+        let output = exchange(
+            &config,
+            b"POST /files/two.txt HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+              6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\nX-Trailer: ignored\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("two.txt")).unwrap(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn chunk_sizes_that_would_overflow_are_refused() {
+        let config = config_for(&scratch_dir("chunked-overflow"));
+        let output = exchange(
+            &config,
+            b"POST /files/big HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+              1\r\na\r\nffffffffffffffff\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).status, 413);
+    }
+
+    #[tokio::test]
+    async fn endless_chunk_size_lines_are_refused() {
+        let config = config_for(&scratch_dir("chunked-endless"));
+        let mut request =
+            b"POST /files/big HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n1"
+                .to_vec();
+        request.extend(std::iter::repeat_n(b';', 64 * 1024));
+        let output = exchange(&config, &request).await;
+        assert_eq!(response(&output).status, 400);
+    }
+
+    #[tokio::test]
+    async fn uploads_larger_than_the_read_buffer_arrive_intact() {
+        let dir = scratch_dir("read-buffer");
+        let config = Config {
+            read_buffer_size: 16,
+            ..config_for(&dir)
+        };
+        let body: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut request = format!(
+            "PUT /files/upload.bin HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+        let output = exchange(&config, &request).await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("upload.bin")).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_a_400_instead_of_a_panic() {
+        let config = config_for(Path::new("."));
+        for request in [
+            &b"GET\r\n\r\n"[..],
+            b"GET /\r\nHost: test\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: test\r\nNoColonHere\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\nHost: test\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: test\r\nX-Bytes: \xfe\xff\r\n\r\n",
+        ] {
+            let output = exchange(&config, request).await;
+            let response = response(&output);
+            assert_eq!(response.status, 400, "{}", String::from_utf8_lossy(request));
+            assert_eq!(response.header("Connection"), Some("close"));
+        }
+        let output = exchange(&config, b"GET /user-agent HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response(&output).status, 400);
+    }
+
+    /// Reads from `client` until `wanted` has arrived, returning all of it.
+    async fn read_until(client: &mut (impl AsyncRead + Unpin), wanted: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut buffer = [0; 1024];
+        while find(&output, wanted).is_none() {
+            let read = client.read(&mut buffer).await.unwrap();
+            assert!(
+                read > 0,
+                "closed before {:?}",
+                String::from_utf8_lossy(wanted)
+            );
+            output.extend_from_slice(&buffer[..read]);
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn expect_100_continue_gets_an_interim_response_first() {
+        let dir = scratch_dir("expect-continue");
+        let config = config_for(&dir);
+        let output = converse(&config, |mut client| async move {
+            client
+                .write_all(
+                    b"PUT /files/big.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            // Nothing of the body is sent until the server asks for it.
+            let interim = read_until(&mut client, b"\r\n\r\n").await;
+            assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+            client.write_all(b"hello").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("big.txt")).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn expect_100_continue_for_an_oversized_body_is_refused() {
+        let config = Config {
+            max_body_size: 4,
+            ..config_for(&scratch_dir("expect-too-large"))
+        };
+        let output = exchange(
+            &config,
+            b"PUT /files/big.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).status, 417);
+    }
+
+    #[tokio::test]
+    async fn expectations_from_http_1_0_clients_are_ignored() {
+        let dir = scratch_dir("expect-http-1-0");
+        let config = config_for(&dir);
+        for expect in ["100-continue", "something-else"] {
+            let request = format!(
+                "PUT /files/old.txt HTTP/1.0\r\nContent-Length: 2\r\nExpect: {}\r\n\r\nhi",
+                expect
+            );
+            let output = exchange(&config, request.as_bytes()).await;
+            assert!(
+                output.starts_with(b"HTTP/1.0 20"),
+                "{}",
+                String::from_utf8_lossy(&output)
+            );
+            assert_eq!(responses(&output).len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_body_on_a_get_is_consumed_before_the_next_request() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/first HTTP/1.1\r\nHost: test\r\nContent-Length: 20\r\n\r\n\
+              GET /echo/smuggled\r\n\
+              GET /echo/second HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].text(), "first");
+        assert_eq!(responses[1].text(), "second");
+    }
+
+    #[tokio::test]
+    async fn http_1_0_connections_close_after_one_response() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/one HTTP/1.0\r\n\r\nGET /echo/two HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert!(output.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header("Connection"), Some("close"));
+        assert_eq!(responses[0].text(), "one");
+    }
+
+    #[tokio::test]
+    async fn http_1_0_connections_persist_when_asked_to() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/one HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /echo/two HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("Connection"), Some("keep-alive"));
+        assert_eq!(responses[1].header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn a_request_line_that_never_finishes_times_out_with_408() {
+        let config = Config {
+            read_timeout: Duration::from_millis(100),
+            ..config_for(Path::new("."))
+        };
+        let output = converse(&config, |mut client| async move {
+            // Each piece arrives well inside the deadline, but the line
+            // as a whole never does.
+            for piece in [&b"GET /ec"[..], b"ho/sl", b"ow HTTP"] {
+                client.write_all(piece).await.unwrap();
+                time::sleep(Duration::from_millis(40)).await;
+            }
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await;
+        let response = response(&output);
+        assert_eq!(response.status, 408);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn every_status_code_has_its_standard_reason_phrase() {
+        let standard = [
+            (101, "Switching Protocols"),
+            (200, "OK"),
+            (201, "Created"),
+            (202, "Accepted"),
+            (204, "No Content"),
+            (206, "Partial Content"),
+            (301, "Moved Permanently"),
+            (302, "Found"),
+            (304, "Not Modified"),
+            (400, "Bad Request"),
+            (401, "Unauthorized"),
+            (403, "Forbidden"),
+            (404, "Not Found"),
+            (405, "Method Not Allowed"),
+            (406, "Not Acceptable"),
+            (408, "Request Timeout"),
+            (409, "Conflict"),
+            (410, "Gone"),
+            (411, "Length Required"),
+            (412, "Precondition Failed"),
+            (413, "Payload Too Large"),
+            (414, "URI Too Long"),
+            (415, "Unsupported Media Type"),
+            (416, "Range Not Satisfiable"),
+            (417, "Expectation Failed"),
+            (429, "Too Many Requests"),
+            (431, "Request Header Fields Too Large"),
+            (500, "Internal Server Error"),
+            (501, "Not Implemented"),
+            (502, "Bad Gateway"),
+            (503, "Service Unavailable"),
+            (505, "HTTP Version Not Supported"),
+        ];
+        // Walking every code rather than the table alone catches a variant
+        // added without a phrase here.
+        for code in 100..600 {
+            let phrase = standard.iter().find(|(known, _)| *known == code);
+            match (HTTPStatusCode::try_from(code), phrase) {
+                (Ok(status), Some((_, phrase))) => {
+                    assert_eq!(status as u16, code);
+                    assert_eq!(status.reason_phrase(), *phrase, "{}", code);
+                }
+                (Err(()), None) => {}
+                (status, phrase) => panic!("{}: {:?} vs {:?}", code, status, phrase),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_sent_in_many_writes_are_read_in_full() {
+        let dir = scratch_dir("many-writes");
+        let config = config_for(&dir);
+        let body: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 253) as u8).collect();
+        let output = converse(&config, |mut client| {
+            let body = body.clone();
+            async move {
+                let head = format!(
+                    "POST /files/upload.bin HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                );
+                client.write_all(head.as_bytes()).await.unwrap();
+                for piece in body.chunks(5000) {
+                    client.write_all(piece).await.unwrap();
+                    client.flush().await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+                client.shutdown().await.unwrap();
+                let mut output = Vec::new();
+                client.read_to_end(&mut output).await.unwrap();
+                output
+            }
+        })
+        .await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("upload.bin")).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn uploads_cut_short_get_a_400() {
+        let dir = scratch_dir("cut-short");
+        let config = config_for(&dir);
+        let output = exchange(
+            &config,
+            b"POST /files/short.bin HTTP/1.1\r\nHost: test\r\nContent-Length: 100\r\n\r\nonly this",
+        )
+        .await;
+        assert_eq!(response(&output).status, 400);
+        assert!(!dir.join("short.bin").exists());
+    }
+
+    /// Parses the one request in `input`, which must be well formed.
+    pub(crate) async fn parse(input: &[u8]) -> HTTPRequest {
+        let (mut client, server) = io::duplex(1 << 16);
+        client.write_all(input).await.unwrap();
+        let mut reader = BufReader::new(server);
+        match HTTPRequest::parse(&mut reader, &config_for(Path::new("."))).await {
+            Ok(Some(request)) => request,
+            _ => panic!("{:?} didn't parse", String::from_utf8_lossy(input)),
+        }
+    }
+
+    #[tokio::test]
+    async fn parses_a_get_with_headers() {
+        let request = parse(
+            b"GET /echo/abc?x=1 HTTP/1.1\r\nHost: Example.com:4221\r\nUser-Agent: curl/8.0\r\n\
+              Accept: text/plain\r\nACCEPT: application/json\r\n\r\n",
+        )
+        .await;
+        assert_eq!(request.method, HTTPMethod::GET);
+        assert_eq!(request.path, "/echo/abc");
+        assert_eq!(request.target, "/echo/abc?x=1");
+        assert!(matches!(request.version, HTTPVersion::V1_1));
+        assert_eq!(request.headers["user-agent"], "curl/8.0");
+        assert_eq!(request.headers["accept"], "text/plain, application/json");
+        assert_eq!(request.host.as_deref(), Some("example.com"));
+        assert!(request.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parses_a_post_with_a_body() {
+        let request = parse(
+            b"POST /files/note.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 11\r\n\r\nhello world",
+        )
+        .await;
+        assert_eq!(request.method, HTTPMethod::POST);
+        assert_eq!(request.path, "/files/note.txt");
+        assert_eq!(request.headers["content-length"], "11");
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_share_one_connection() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\nGET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        for response in &responses {
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Connection"), Some("keep-alive"));
+            assert_eq!(response.text(), "abc");
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_close_ends_the_connection_after_its_response() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/one HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n\
+              GET /echo/two HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header("Connection"), Some("close"));
+        assert_eq!(responses[0].text(), "one");
+    }
+
+    #[tokio::test]
+    async fn the_query_string_is_split_from_the_path() {
+        let request = parse(b"GET /echo/hello?times=3&loud HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(request.path, "/echo/hello");
+        assert_eq!(request.query["times"], "3");
+        assert_eq!(request.query["loud"], "");
+        let request = parse(b"GET /echo/hello HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(request.path, "/echo/hello");
+        assert!(request.query.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_lets_in_flight_requests_finish() {
+        use tokio::net::UnixStream;
+        use tokio::sync::oneshot;
+
+        let dir = scratch_dir("shutdown");
+        let path = dir.join("server.sock");
+        let config = Config {
+            unix_socket: Some(path.clone()),
+            ..config_for(&dir)
+        };
+        let listener = Listener::bind(&config).unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let server = tokio::spawn(run(listener, Arc::new(config), None, &METRICS, async {
+            let _ = stopped.await;
+        }));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /echo/first HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        read_until(&mut stream, b"first").await;
+        // Shut down partway through the next request on the connection.
+        stream
+            .write_all(b"GET /echo/second HTTP/1.1\r\nHost: test\r\n")
+            .await
+            .unwrap();
+        stop.send(()).unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(UnixStream::connect(&path).await.is_err());
+
+        stream
+            .write_all(b"Connection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        let response = response(&output);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "second");
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server still running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_size_limit_are_refused() {
+        let dir = scratch_dir("body-limit");
+        let config = Config {
+            max_body_size: 10,
+            ..config_for(&dir)
+        };
+        let upload = |name: &str, body: &str| {
+            format!(
+                "POST /files/{} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}",
+                name,
+                body.len(),
+                body
+            )
+        };
+        let output = exchange(&config, upload("fits.txt", "0123456789").as_bytes()).await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("fits.txt")).unwrap(), b"0123456789");
+
+        let output = exchange(&config, upload("too-big.txt", "0123456789a").as_bytes()).await;
+        let refused = response(&output);
+        assert_eq!(refused.status, 413);
+        assert_eq!(refused.header("Connection"), Some("close"));
+        assert!(!dir.join("too-big.txt").exists());
+
+        // A huge declared length is refused before anything is read.
+        let output = exchange(
+            &config,
+            b"POST /files/huge HTTP/1.1\r\nHost: test\r\nContent-Length: 10000000000\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).status, 413);
+    }
+
+    #[tokio::test]
+    async fn header_names_match_in_any_case() {
+        let dir = scratch_dir("header-case");
+        let config = config_for(&dir);
+        for user_agent in ["user-agent", "USER-AGENT", "uSeR-aGeNt"] {
+            let request = format!(
+                "GET /user-agent HTTP/1.1\r\nhost: test\r\n{}: probe/1.0\r\n\r\n",
+                user_agent
+            );
+            let output = exchange(&config, request.as_bytes()).await;
+            assert_eq!(response(&output).text(), "probe/1.0", "{}", user_agent);
+        }
+        let output = exchange(
+            &config,
+            b"POST /files/cased.txt HTTP/1.1\r\nHOST: test\r\ncontent-LENGTH: 5\r\n\r\nlower",
+        )
+        .await;
+        assert_eq!(response(&output).status, 201);
+        assert_eq!(std::fs::read(dir.join("cased.txt")).unwrap(), b"lower");
+    }
+
+    #[tokio::test]
+    async fn posts_need_a_valid_content_length() {
+        let dir = scratch_dir("content-length");
+        let config = config_for(&dir);
+        let output = exchange(
+            &config,
+            b"POST /files/unsized.txt HTTP/1.1\r\nHost: test\r\n\r\nbody",
+        )
+        .await;
+        assert_eq!(response(&output).status, 411);
+        for length in ["-1", "ten", "1e3", "5, 6", ""] {
+            let request = format!(
+                "POST /files/bad.txt HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\nbody",
+                length
+            );
+            let output = exchange(&config, request.as_bytes()).await;
+            assert_eq!(response(&output).status, 400, "{:?}", length);
+        }
+        assert!(!dir.join("unsized.txt").exists());
+        assert!(!dir.join("bad.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn stalling_mid_headers_times_out_with_408() {
+        let config = Config {
+            read_timeout: Duration::from_millis(100),
+            ..config_for(Path::new("."))
+        };
+        let output = converse(&config, |mut client| async move {
+            client
+                .write_all(b"GET /echo/abc HTTP/1.1\r\nHost: te")
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await;
+        let response = response(&output);
+        assert_eq!(response.status, 408);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn stalling_mid_body_times_out_with_408() {
+        let dir = scratch_dir("stalled-body");
+        let config = Config {
+            read_timeout: Duration::from_millis(100),
+            ..config_for(&dir)
+        };
+        let output = converse(&config, |mut client| async move {
+            client
+                .write_all(
+                    b"POST /files/partial HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nhalf",
+                )
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await;
+        assert_eq!(response(&output).status, 408);
+        assert!(!dir.join("partial").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_for_a_permit() {
+        use tokio::net::UnixStream;
+
+        let dir = scratch_dir("max-connections");
+        let path = dir.join("server.sock");
+        let config = Config {
+            unix_socket: Some(path.clone()),
+            max_connections: Some(2),
+            ..config_for(&dir)
+        };
+        let listener = Listener::bind(&config).unwrap();
+        let server = tokio::spawn(run(
+            listener,
+            Arc::new(config),
+            None,
+            &METRICS,
+            std::future::pending(),
+        ));
+
+        let mut first = UnixStream::connect(&path).await.unwrap();
+        let _second = UnixStream::connect(&path).await.unwrap();
+        let mut third = UnixStream::connect(&path).await.unwrap();
+        third
+            .write_all(b"GET /echo/third HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        let waited = time::timeout(Duration::from_millis(200), third.read(&mut [0; 1])).await;
+        assert!(waited.is_err(), "the third connection was served early");
+
+        first
+            .write_all(b"GET /echo/first HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        first.read_to_end(&mut output).await.unwrap();
+        assert_eq!(response(&output).text(), "first");
+
+        output.clear();
+        time::timeout(Duration::from_secs(1), third.read_to_end(&mut output))
+            .await
+            .expect("the third connection was never served")
+            .unwrap();
+        assert_eq!(response(&output).text(), "third");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_request_version() {
+        let config = config_for(Path::new("."));
+        let output = exchange(&config, b"GET /echo/old HTTP/1.0\r\n\r\n").await;
+        assert!(output.starts_with(b"HTTP/1.0 200 OK\r\n"));
+        let output = exchange(&config, b"GET /echo/new HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        for version in ["HTTP/9.9", "HTTP/1.2", "HTCPCP/1.0"] {
+            let request = format!("GET /echo/abc {}\r\nHost: test\r\n\r\n", version);
+            let output = exchange(&config, request.as_bytes()).await;
+            assert_eq!(response(&output).status, 505, "{}", version);
+        }
+    }
+
+    #[test]
+    fn the_builder_keeps_header_order_and_sets_content_length() {
+        let response = HTTPResponse::ok()
+            .with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_content_type("text/plain")
+            .with_header("X-First", 1)
+            .with_vary("Accept")
+            .with_vary("accept")
+            .with_vary("Accept-Encoding")
+            .with_body(b"hello".to_vec());
+        assert_eq!(
+            String::from_utf8(response.format(HTTPVersion::V1_1)).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Type: text/plain\r\n\
+             X-First: 1\r\n\
+             Vary: Accept, Accept-Encoding\r\n\
+             Content-Length: 5\r\n\
+             \r\n\
+             hello"
+        );
+    }
+
+    #[test]
+    fn bodiless_responses_get_a_zero_length_unless_they_cannot_have_a_body() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let not_found = HTTPResponse::new(HTTPStatusCode::NotFound).with_header("Date", date);
+        assert_eq!(
+            not_found.format(HTTPVersion::V1_1),
+            format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nDate: {}\r\n\r\n",
+                date
+            )
+            .into_bytes()
+        );
+        let no_content = HTTPResponse::new(HTTPStatusCode::NoContent).with_header("Date", date);
+        assert_eq!(
+            no_content.format(HTTPVersion::V1_0),
+            format!("HTTP/1.0 204 No Content\r\nDate: {}\r\n\r\n", date).into_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn cors_headers_are_sent_only_when_configured() {
+        let get = b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n";
+        let output = exchange(&config_for(Path::new(".")), get).await;
+        assert_eq!(
+            response(&output).header("Access-Control-Allow-Origin"),
+            None
+        );
+
+        let config = Config {
+            cors_origin: Some("https://app.example".to_string()),
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(&config, get).await;
+        let response = response(&output);
+        assert_eq!(
+            response.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert_eq!(response.header("Access-Control-Allow-Methods"), None);
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_list_the_allowed_methods_and_headers() {
+        let config = Config {
+            cors_origin: Some("*".to_string()),
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(
+            &config,
+            b"OPTIONS /files/upload.txt HTTP/1.1\r\nHost: test\r\nOrigin: https://app.example\r\n\
+              Access-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: X-Token\r\n\r\n",
+        )
+        .await;
+        let response = response(&output);
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(
+            response.header("Access-Control-Allow-Methods"),
+            Some("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Headers"),
+            Some("X-Token")
+        );
+    }
+
+    #[tokio::test]
+    async fn json_errors_describe_the_status() {
+        let request = b"GET /nowhere HTTP/1.1\r\nHost: test\r\n\r\n";
+        let output = exchange(&config_for(Path::new(".")), request).await;
+        assert!(response(&output).body.is_empty());
+
+        let config = Config {
+            json_errors: true,
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(&config, request).await;
+        let not_found = response(&output);
+        assert_eq!(not_found.status, 404);
+        assert_eq!(not_found.header("Content-Type"), Some("application/json"));
+        assert_eq!(not_found.text(), r#"{"error":"Not Found","status":404}"#);
+        let length = not_found.body.len().to_string();
+        assert_eq!(not_found.header("Content-Length"), Some(length.as_str()));
+
+        let output = exchange(&config, b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(
+            response(&output).text(),
+            r#"{"error":"Bad Request","status":400}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_gets_a_500_and_spares_the_server() {
+        fn boom(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+            panic!("handler bug");
+        }
+        fn fine(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+            HTTPResponse::ok().with_body(b"fine".to_vec())
+        }
+        let mut router = Router::new();
+        router.register(HTTPMethod::GET, "/boom", boom);
+        router.register(HTTPMethod::GET, "/fine", fine);
+        let config = config_for(Path::new("."));
+        let send = |request: &'static [u8]| {
+            converse_routed(&config, &router, move |mut client| async move {
+                client.write_all(request).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut output = Vec::new();
+                client.read_to_end(&mut output).await.unwrap();
+                output
+            })
+        };
+
+        let output = send(
+            b"GET /boom HTTP/1.1\r\nHost: test\r\n\r\nGET /fine HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 500);
+        assert_eq!(responses[0].header("Connection"), Some("close"));
+
+        let output = send(b"GET /fine HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response(&output).text(), "fine");
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_after_the_keepalive_timeout() {
+        let config = Config {
+            keepalive_timeout: Duration::from_millis(100),
+            ..config_for(Path::new("."))
+        };
+        let started = Instant::now();
+        let output = converse(&config, |mut client| async move {
+            client
+                .write_all(b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            // Never send another request, nor close our side.
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        })
+        .await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let response = response(&output);
+        assert_eq!(response.header("Connection"), Some("keep-alive"));
+        assert_eq!(response.text(), "abc");
+    }
+
+    #[tokio::test]
+    async fn the_last_allowed_request_is_told_the_connection_closes() {
+        let config = Config {
+            max_requests_per_conn: 3,
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(
+            &config,
+            &b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n".repeat(5),
+        )
+        .await;
+        let connections: Vec<_> = responses(&output)
+            .iter()
+            .map(|response| response.header("Connection").unwrap().to_string())
+            .collect();
+        assert_eq!(connections, ["keep-alive", "keep-alive", "close"]);
+    }
+
+    #[tokio::test]
+    async fn every_response_carries_a_request_id() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /echo/a HTTP/1.1\r\nHost: test\r\n\r\nGET /nowhere HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let ids: Vec<_> = responses(&output)
+            .iter()
+            .map(|response| response.header("X-Request-Id").unwrap().to_string())
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids[0].is_empty());
+        assert_ne!(ids[0], ids[1]);
+
+        let output = exchange(
+            &config,
+            b"GET /echo/a HTTP/1.1\r\nHost: test\r\nX-Request-Id: trace-42/abc\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            response(&output).header("X-Request-Id"),
+            Some("trace-42/abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn the_server_closes_the_socket_after_connection_close() {
+        let config = config_for(Path::new("."));
+        let output = converse(&config, |mut client| async move {
+            client
+                .write_all(b"GET /echo/bye HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            // Our side stays open, so reaching the end means the server
+            // closed its own.
+            let mut output = Vec::new();
+            time::timeout(Duration::from_secs(1), client.read_to_end(&mut output))
+                .await
+                .expect("the server kept the connection open")
+                .unwrap();
+            output
+        })
+        .await;
+        let response = response(&output);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert_eq!(response.text(), "bye");
+    }
+
+    #[tokio::test]
+    async fn configured_headers_are_added_to_every_response() {
+        let config = Config {
+            headers: vec![
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                (
+                    "Strict-Transport-Security".to_string(),
+                    "max-age=63072000".to_string(),
+                ),
+                ("Content-Length".to_string(), "999".to_string()),
+            ],
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(&config, b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let response = response(&output);
+        assert_eq!(response.header("X-Frame-Options"), Some("DENY"));
+        assert_eq!(
+            response.header("Strict-Transport-Security"),
+            Some("max-age=63072000")
+        );
+        let lengths: Vec<_> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .collect();
+        assert_eq!(lengths.len(), 1, "{:?}", lengths);
+        assert_eq!(response.header("Content-Length"), Some("3"));
+        assert_eq!(response.text(), "abc");
+    }
+
+    #[tokio::test]
+    async fn responses_are_dated_now() {
+        let config = config_for(Path::new("."));
+        let output = exchange(&config, b"GET /nowhere HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let date = response(&output).header("Date").unwrap().to_string();
+        let sent = date::parse_http_date(&date).expect("not an HTTP-date");
+        let now = std::time::SystemTime::now();
+        let skew = now.duration_since(sent).unwrap_or_else(|e| e.duration());
+        assert!(skew < Duration::from_secs(5), "{} is {:?} off", date, skew);
+    }
+
+    #[tokio::test]
+    async fn the_server_header_can_be_renamed_or_dropped() {
+        let request = b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n";
+        let server = |config: Config| async move {
+            let output = exchange(&config, request).await;
+            response(&output).header("Server").map(str::to_string)
+        };
+        let default = server(config_for(Path::new("."))).await.unwrap();
+        assert_eq!(
+            default,
+            concat!("http-server-rust/", env!("CARGO_PKG_VERSION"))
+        );
+        let custom = Config {
+            server_token: Some("edge/2".to_string()),
+            ..config_for(Path::new("."))
+        };
+        assert_eq!(server(custom).await.as_deref(), Some("edge/2"));
+        let suppressed = Config {
+            server_token: None,
+            ..config_for(Path::new("."))
+        };
+        assert_eq!(server(suppressed).await, None);
+    }
+
+    #[tokio::test]
+    async fn oversized_request_heads_are_refused() {
+        let config = Config {
+            max_header_size: 1024,
+            ..config_for(Path::new("."))
+        };
+        let long_uri = format!(
+            "GET /echo/{} HTTP/1.1\r\nHost: test\r\n\r\n",
+            "a".repeat(2000)
+        );
+        let output = exchange(&config, long_uri.as_bytes()).await;
+        assert_eq!(response(&output).status, 414);
+
+        // No one header is too long, but together they are.
+        let mut many_headers = "GET /echo/abc HTTP/1.1\r\nHost: test\r\n".to_string();
+        for i in 0..20 {
+            many_headers.push_str(&format!("X-Padding-{}: {}\r\n", i, "p".repeat(80)));
+        }
+        many_headers.push_str("\r\n");
+        let output = exchange(&config, many_headers.as_bytes()).await;
+        let response = response(&output);
+        assert_eq!(response.status, 431);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn repeated_headers_keep_every_value() {
+        let request = parse(
+            b"GET / HTTP/1.1\r\nHost: test\r\nX-Custom: first\r\nCookie: a=1\r\n\
+              x-custom: second\r\nCookie: b=2\r\n\r\n",
+        )
+        .await;
+        assert_eq!(request.headers["x-custom"], "first, second");
+        assert_eq!(
+            request.get_all("X-Custom").collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+        assert_eq!(request.headers["cookie"], "a=1; b=2");
+        assert_eq!(request.cookies().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_rate_limit_get_a_429() {
+        let config = config_for(Path::new("."));
+        let router = handlers::router();
+        let limiter = RateLimiter::new(2.0);
+        let (mut client, server_end) = io::duplex(1 << 16);
+        let server = async {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(
+                &mut reader,
+                peer(),
+                &config,
+                &router,
+                &Metrics::new(),
+                Some(&limiter),
+            )
+            .await
+        };
+        let client = async move {
+            let request = b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n";
+            client.write_all(&request.repeat(5)).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let (served, output) = tokio::join!(server, client);
+        served.unwrap();
+        let responses = responses(&output);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[1].status, 200);
+        let limited = responses.last().unwrap();
+        assert_eq!(limited.status, 429);
+        assert_eq!(limited.header("Retry-After"), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn unix_socket_peers_are_not_rate_limited() {
+        let config = config_for(Path::new("."));
+        let router = handlers::router();
+        let limiter = RateLimiter::new(2.0);
+        let (mut client, server_end) = io::duplex(1 << 16);
+        let server = async {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(
+                &mut reader,
+                Peer::Unix,
+                &config,
+                &router,
+                &Metrics::new(),
+                Some(&limiter),
+            )
+            .await
+        };
+        let client = async move {
+            let request = b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n";
+            client.write_all(&request.repeat(5)).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let (served, output) = tokio::join!(server, client);
+        served.unwrap();
+        let statuses: Vec<u16> = responses(&output).iter().map(|r| r.status).collect();
+        assert_eq!(statuses, [200; 5]);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_are_answered_with_101() {
+        let config = config_for(Path::new("."));
+        let output = exchange(
+            &config,
+            b"GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\n\
+              Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await;
+        let switched = response(&output);
+        assert_eq!(switched.status, 101);
+        assert_eq!(switched.header("Upgrade"), Some("websocket"));
+        assert_eq!(switched.header("Connection"), Some("Upgrade"));
+        assert_eq!(
+            switched.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+
+        // The key must encode 16 bytes.
+        let output = exchange(
+            &config,
+            b"GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dG9vIHNob3J0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).status, 400);
+    }
+
+    #[tokio::test]
+    async fn error_pages_replace_empty_error_bodies() {
+        let pages = scratch_dir("error-pages");
+        std::fs::write(pages.join("404.html"), "<h1>lost</h1>").unwrap();
+        let config = Config {
+            error_pages: Some(pages.clone()),
+            ..config_for(&pages)
+        };
+        let output = exchange(&config, b"GET /nowhere HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let not_found = response(&output);
+        assert_eq!(not_found.status, 404);
+        assert_eq!(not_found.header("Content-Type"), Some("text/html"));
+        assert_eq!(not_found.text(), "<h1>lost</h1>");
+
+        // There's no 403.html, so that one stays as it was.
+        let output = exchange(
+            &config,
+            b"GET /files/..%2F..%2Fetc HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let forbidden = response(&output);
+        assert_eq!(forbidden.status, 403);
+        assert!(forbidden.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_client_that_closes_at_once_gets_no_response() {
+        let config = config_for(Path::new("."));
+        assert!(exchange(&config, b"").await.is_empty());
+        let output = exchange(&config, b"GET /echo/ab").await;
+        let response = response(&output);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn a_pipelined_post_body_is_not_read_as_the_next_request() {
+        let dir = scratch_dir("pipelined-post");
+        let config = config_for(&dir);
+        let output = exchange(
+            &config,
+            b"POST /files/posted.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 29\r\n\r\n\
+              GET /echo/smuggled HTTP/1.1\r\n\
+              GET /echo/after HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].status, 201);
+        assert_eq!(responses[1].status, 200);
+        assert_eq!(responses[1].text(), "after");
+        assert_eq!(
+            std::fs::read(dir.join("posted.txt")).unwrap(),
+            b"GET /echo/smuggled HTTP/1.1\r\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn once_serves_a_single_connection_then_returns() {
+        use tokio::net::UnixStream;
+
+        let dir = scratch_dir("once");
+        std::fs::write(dir.join("index.html"), "just once").unwrap();
+        let path = dir.join("server.sock");
+        let config = Config {
+            unix_socket: Some(path.clone()),
+            once: true,
+            ..config_for(&dir)
+        };
+        let listener = Listener::bind(&config).unwrap();
+        let server = tokio::spawn(run(
+            listener,
+            Arc::new(config),
+            None,
+            &METRICS,
+            std::future::pending(),
+        ));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(response(&output).text(), "just once");
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("still running after its one connection")
+            .unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn ambiguous_body_lengths_are_refused() {
+        let config = config_for(&scratch_dir("smuggling"));
+        for request in [
+            &b"POST /files/x HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\
+               Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n"[..],
+            b"POST /files/x HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\
+              Content-Length: 6\r\n\r\nhello!",
+            b"POST /files/x HTTP/1.1\r\nHost: test\r\nContent-Length: 5, 6\r\n\r\nhello!",
+        ] {
+            let output = exchange(&config, request).await;
+            let response = response(&output);
+            assert_eq!(response.status, 400, "{}", String::from_utf8_lossy(request));
+            assert_eq!(response.header("Connection"), Some("close"));
+        }
+        // Repeating the same length is harmless.
+        let output = exchange(
+            &config,
+            b"POST /files/x HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert_eq!(response(&output).status, 201);
+    }
+
+    #[tokio::test]
+    async fn absolute_form_targets_yield_their_path_and_host() {
+        let request =
+            parse(b"GET http://Example.com:8080/echo/abc?x=1 HTTP/1.1\r\nHost: other.test\r\n\r\n")
+                .await;
+        assert_eq!(request.path, "/echo/abc");
+        assert_eq!(request.target, "/echo/abc?x=1");
+        assert_eq!(request.query["x"], "1");
+        assert_eq!(request.host.as_deref(), Some("example.com"));
+
+        let request = parse(b"GET http://example.com HTTP/1.0\r\n\r\n").await;
+        assert_eq!(request.path, "/");
+
+        let output = exchange(
+            &config_for(Path::new(".")),
+            b"GET https://example.com/echo/absolute HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).text(), "absolute");
+    }
+
+    #[tokio::test]
+    async fn http_1_1_requests_need_a_host() {
+        let config = config_for(Path::new("."));
+        for (request, status) in [
+            (&b"GET /echo/abc HTTP/1.1\r\n\r\n"[..], 400),
+            (b"GET /echo/abc HTTP/1.1\r\nHost: example.com\r\n\r\n", 200),
+            (b"GET /echo/abc HTTP/1.0\r\n\r\n", 200),
+        ] {
+            let output = exchange(&config, request).await;
+            assert_eq!(
+                response(&output).status,
+                status,
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+        }
+        let request = parse(b"GET / HTTP/1.1\r\nHost: WWW.Example.com:4221\r\n\r\n").await;
+        assert_eq!(request.host.as_deref(), Some("www.example.com"));
+        let request = parse(b"GET / HTTP/1.1\r\nHost: [::1]:4221\r\n\r\n").await;
+        assert_eq!(request.host.as_deref(), Some("[::1]"));
+    }
+
+    #[tokio::test]
+    async fn chunked_responses_reassemble_to_the_stream() {
+        let stream = (&b"hello "[..]).chain(&b"world"[..]);
+        let mut output = Vec::new();
+        HTTPResponse::ok()
+            .with_chunked(Box::new(stream))
+            .write_to(HTTPVersion::V1_1, &mut output)
+            .await
+            .unwrap();
+        let text = String::from_utf8_lossy(&output);
+        assert!(
+            text.contains("\r\nTransfer-Encoding: chunked\r\n"),
+            "{}",
+            text
+        );
+        assert!(!text.contains("Content-Length"), "{}", text);
+        assert!(
+            text.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"),
+            "{}",
+            text
+        );
+        assert_eq!(response(&output).text(), "hello world");
+
+        // HTTP/1.0 has no chunked coding, so the body just runs to the end.
+        let stream = (&b"hello "[..]).chain(&b"world"[..]);
+        let mut output = Vec::new();
+        HTTPResponse::ok()
+            .with_chunked(Box::new(stream))
+            .write_to(HTTPVersion::V1_0, &mut output)
+            .await
+            .unwrap();
+        let text = String::from_utf8_lossy(&output);
+        assert!(!text.contains("Transfer-Encoding"), "{}", text);
+        assert!(text.ends_with("\r\n\r\nhello world"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods_are_not_implemented() {
+        let config = config_for(Path::new("."));
+        for method in ["TRACE", "CONNECT", "BREW", "get"] {
+            let request = format!("{} / HTTP/1.1\r\nHost: test\r\n\r\n", method);
+            let output = exchange(&config, request.as_bytes()).await;
+            assert_eq!(response(&output).status, 501, "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_file_answers_every_get_with_one_file() {
+        let dir = scratch_dir("serve-file");
+        std::fs::write(dir.join("app.html"), "<main></main>").unwrap();
+        let config = Config {
+            serve_file: Some(dir.join("app.html")),
+            ..config_for(&dir)
+        };
+        for path in ["/", "/some/deep/route", "/files/other.txt"] {
+            let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+            let output = exchange(&config, request.as_bytes()).await;
+            let response = response(&output);
+            assert_eq!(response.status, 200, "{}", path);
+            assert_eq!(response.header("Content-Type"), Some("text/html"));
+            assert_eq!(response.text(), "<main></main>");
+        }
+        // Other methods still reach their routes.
+        let output = exchange(
+            &config,
+            b"POST /files/note.txt HTTP/1.1\r\nHost: test\r\nContent-Length: 2\r\n\r\nhi",
+        )
+        .await;
+        assert_eq!(response(&output).status, 201);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_counts_connections_and_requests() {
+        use tokio::net::UnixStream;
+        use tokio::sync::oneshot;
+
+        let dir = scratch_dir("run-counters");
+        let path = dir.join("server.sock");
+        let config = Config {
+            unix_socket: Some(path.clone()),
+            ..config_for(&dir)
+        };
+        let listener = Listener::bind(&config).unwrap();
+        // Counters of its own, which no other test's traffic reaches.
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let (stop, stopped) = oneshot::channel();
+        let server = tokio::spawn(run(listener, Arc::new(config), None, metrics, async {
+            let _ = stopped.await;
+        }));
+
+        for _ in 0..2 {
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            stream
+                .write_all(
+                    b"GET /echo/a HTTP/1.1\r\nHost: test\r\n\r\n\
+                      GET /echo/b HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).await.unwrap();
+            assert_eq!(responses(&output).len(), 2);
+        }
+        stop.send(()).unwrap();
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server still running")
+            .unwrap();
+        assert_eq!(metrics.accepted(), 2);
+        assert_eq!(metrics.requests(), 4);
+        assert_eq!(
+            metrics.summary(),
+            "served 4 request(s) over 2 connection(s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_header_count_are_refused() {
+        let config = Config {
+            max_headers: 3,
+            ..config_for(Path::new("."))
+        };
+        let with_headers = |count: usize| {
+            let mut request = "GET /echo/abc HTTP/1.1\r\nHost: test\r\n".to_string();
+            for i in 1..count {
+                request.push_str(&format!("X-Extra-{}: {}\r\n", i, i));
+            }
+            request + "\r\n"
+        };
+        let output = exchange(&config, with_headers(3).as_bytes()).await;
+        assert_eq!(response(&output).status, 200);
+
+        let output = exchange(&config, with_headers(4).as_bytes()).await;
+        let refused = response(&output);
+        assert_eq!(refused.status, 431);
+        assert_eq!(refused.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_leaves_only_monitoring_up() {
+        let config = Config {
+            maintenance: true,
+            ..config_for(Path::new("."))
+        };
+        let output = exchange(&config, b"GET /echo/abc HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let unavailable = response(&output);
+        assert_eq!(unavailable.status, 503);
+        assert_eq!(unavailable.header("Retry-After"), Some("60"));
+
+        for path in ["/health", "/metrics"] {
+            let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+            let output = exchange(&config, request.as_bytes()).await;
+            assert_eq!(response(&output).status, 200, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_leaving_mid_response_are_a_disconnect() {
+        let dir = scratch_dir("disconnect");
+        std::fs::write(dir.join("large.bin"), vec![b'x'; 1 << 16]).unwrap();
+        let config = config_for(&dir);
+        let router = handlers::router();
+        // A small pipe so the server is still writing when the client goes.
+        let (mut client, server_end) = io::duplex(256);
+        let server = async {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(&mut reader, peer(), &config, &router, &Metrics::new(), None).await
+        };
+        let client = async move {
+            client
+                .write_all(b"GET /files/large.bin HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            let mut start = [0; 64];
+            client.read_exact(&mut start).await.unwrap();
+        };
+        let (served, ()) = tokio::join!(server, client);
+        let error = served.unwrap_err();
+        assert!(is_disconnect(&error), "{}", error);
+
+        // The next connection is served as usual.
+        let output = exchange(
+            &config,
+            b"GET /echo/still-up HTTP/1.1\r\nHost: test\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response(&output).text(), "still-up");
+    }
+
+    #[tokio::test]
+    async fn a_file_shorter_than_its_length_fails_the_response() {
+        let dir = scratch_dir("short-file");
+        std::fs::write(dir.join("shrunk.txt"), "12345").unwrap();
+        // As if the file lost half its bytes after its length was taken.
+        let file = File::open(dir.join("shrunk.txt")).await.unwrap();
+        let mut sent = Vec::new();
+        let error = HTTPResponse::ok()
+            .with_file(file, 10)
+            .write_to(HTTPVersion::V1_1, &mut sent)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(sent.ends_with(b"Content-Length: 10\r\n\r\n12345"));
+    }
+
+    #[tokio::test]
+    async fn a_range_past_the_end_of_a_shrunk_file_fails_the_response() {
+        let dir = scratch_dir("short-parts");
+        std::fs::write(dir.join("shrunk.txt"), "12345").unwrap();
+        let file = File::open(dir.join("shrunk.txt")).await.unwrap();
+        let parts = vec![
+            (b"--a\r\n\r\n".to_vec(), ByteRange { start: 0, end: 1 }),
+            (b"\r\n--a\r\n\r\n".to_vec(), ByteRange { start: 3, end: 7 }),
+        ];
+        let mut sent = Vec::new();
+        let error = HTTPResponse::new(HTTPStatusCode::PartialContent)
+            .with_parts(file, parts, b"\r\n--a--\r\n".to_vec())
+            .write_to(HTTPVersion::V1_1, &mut sent)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(sent.ends_with(b"12\r\n--a\r\n\r\n45"));
+    }
+
+    #[tokio::test]
+    async fn connections_count_each_request_by_status() {
+        let config = config_for(Path::new("."));
+        let router = handlers::router();
+        let metrics = Metrics::new();
+        let (mut client, server_end) = io::duplex(1 << 16);
+        let server = async {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(&mut reader, peer(), &config, &router, &metrics, None).await
+        };
+        let client = async move {
+            let counted = b"GET /echo/counted HTTP/1.1\r\nHost: test\r\n\r\n";
+            client.write_all(&counted.repeat(3)).await.unwrap();
+            client
+                .write_all(b"GET /missing HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            client.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let (served, output) = tokio::join!(server, client);
+        served.unwrap();
+        let bytes = output.len();
+        let text = metrics.render();
+        for line in [
+            "http_requests_total 4\n".to_string(),
+            "http_responses_total{class=\"2xx\"} 3\n".to_string(),
+            "http_responses_total{class=\"4xx\"} 1\n".to_string(),
+            format!("http_response_bytes_total {}\n", bytes),
+        ] {
+            assert!(text.contains(&line), "{} missing from\n{}", line, text);
+        }
     }
 }
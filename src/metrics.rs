@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Traffic counters. The process-wide set, `METRICS`, is served in the
+/// Prometheus text format on `/metrics`.
+pub struct Metrics {
+    requests: AtomicU64,
+    /// Responses by status class, 1xx through 5xx.
+    responses: [AtomicU64; 5],
+    bytes: AtomicU64,
+    /// Connections accepted since startup.
+    accepted: AtomicU64,
+    /// Connections open right now.
+    connections: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            requests: AtomicU64::new(0),
+            responses: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            bytes: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts one answered request, refused ones included, and the bytes
+    /// its response took.
+    pub fn record(&self, code: u16, bytes: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = self.responses.get(usize::from(code / 100).wrapping_sub(1)) {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// The one-line total logged at shutdown.
+    pub fn summary(&self) -> String {
+        format!(
+            "served {} request(s) over {} connection(s)",
+            self.requests(),
+            self.accepted()
+        )
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# HELP http_requests_total Requests answered.\n");
+        text.push_str("# TYPE http_requests_total counter\n");
+        text.push_str(&format!("http_requests_total {}\n", self.requests()));
+        text.push_str("# HELP http_responses_total Responses sent, by status class.\n");
+        text.push_str("# TYPE http_responses_total counter\n");
+        for (class, count) in self.responses.iter().enumerate() {
+            text.push_str(&format!(
+                "http_responses_total{{class=\"{}xx\"}} {}\n",
+                class + 1,
+                count.load(Ordering::Relaxed)
+            ));
+        }
+        text.push_str("# HELP http_response_bytes_total Bytes written in responses.\n");
+        text.push_str("# TYPE http_response_bytes_total counter\n");
+        text.push_str(&format!(
+            "http_response_bytes_total {}\n",
+            self.bytes.load(Ordering::Relaxed)
+        ));
+        text.push_str("# HELP http_connections_total Connections accepted.\n");
+        text.push_str("# TYPE http_connections_total counter\n");
+        text.push_str(&format!("http_connections_total {}\n", self.accepted()));
+        text.push_str("# HELP http_connections_active Connections currently open.\n");
+        text.push_str("# TYPE http_connections_active gauge\n");
+        text.push_str(&format!(
+            "http_connections_active {}\n",
+            self.connections.load(Ordering::Relaxed)
+        ));
+        text
+    }
+}
+
+pub struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_what_was_recorded() {
+        let metrics = Metrics::new();
+        metrics.record(200, 100);
+        metrics.record(201, 20);
+        metrics.record(404, 3);
+        let connection = metrics.connection();
+        let text = metrics.render();
+        for line in [
+            "http_requests_total 3\n",
+            "http_responses_total{class=\"2xx\"} 2\n",
+            "http_responses_total{class=\"3xx\"} 0\n",
+            "http_responses_total{class=\"4xx\"} 1\n",
+            "http_response_bytes_total 123\n",
+            "http_connections_total 1\n",
+            "http_connections_active 1\n",
+        ] {
+            assert!(text.contains(line), "{} missing from\n{}", line, text);
+        }
+        drop(connection);
+        assert!(metrics.render().contains("http_connections_active 0\n"));
+    }
+
+    #[test]
+    fn codes_outside_the_classes_only_count_as_requests() {
+        let metrics = Metrics::new();
+        metrics.record(0, 0);
+        metrics.record(600, 0);
+        assert_eq!(metrics.requests(), 2);
+        assert!(!metrics.render().contains("} 1\n"));
+    }
+}
@@ -0,0 +1,127 @@
+/// One part of a `multipart/form-data` body.
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// The `boundary` parameter of a `multipart/form-data` Content-Type, or
+/// `None` for any other type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// Splits `body` into its parts (RFC 7578). Returns `None` when the body
+/// doesn't contain a well-formed sequence of boundary-delimited parts up to
+/// the closing delimiter.
+pub fn parse(body: &[u8], boundary: &str) -> Option<Vec<Part>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // Anything before the first delimiter is a preamble to be ignored.
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+    let mut separator = b"\r\n".to_vec();
+    separator.extend_from_slice(&delimiter);
+
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n")?;
+        let headers_end = find(rest, b"\r\n\r\n")?;
+        let headers = std::str::from_utf8(&rest[..headers_end]).ok()?;
+        rest = &rest[headers_end + 4..];
+        let content_end = find(rest, &separator)?;
+        let data = rest[..content_end].to_vec();
+        rest = &rest[content_end + separator.len()..];
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            data,
+        };
+        for header in headers.split("\r\n") {
+            let (name, value) = header.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                part.name = disposition_param(value, "name");
+                part.filename = disposition_param(value, "filename");
+            }
+        }
+        parts.push(part);
+    }
+}
+
+/// A parameter of a `Content-Disposition: form-data; name="..."` value.
+fn disposition_param(disposition: &str, param: &str) -> Option<String> {
+    disposition.split(';').skip(1).find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_boundary_of_form_data_only() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=----abc123").as_deref(),
+            Some("----abc123")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; BOUNDARY=\"quoted\"").as_deref(),
+            Some("quoted")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=x"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+    }
+
+    #[test]
+    fn splits_fields_and_files() {
+        let body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"beach.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            sand\r\nand sea\r\n\
+            --XyZ--\r\n";
+        let parts = parse(body, "XyZ").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].data, b"holiday");
+        assert_eq!(parts[1].name.as_deref(), Some("photo"));
+        assert_eq!(parts[1].filename.as_deref(), Some("beach.txt"));
+        assert_eq!(parts[1].data, b"sand\r\nand sea");
+    }
+
+    #[test]
+    fn rejects_bodies_without_a_closing_delimiter() {
+        let unterminated = b"--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1";
+        assert!(parse(unterminated, "b").is_none());
+        assert!(parse(b"no delimiters at all", "b").is_none());
+        let headerless = b"--b\r\nnot a header\r\n\r\n1\r\n--b--";
+        assert!(parse(headerless, "b").is_none());
+    }
+}
@@ -0,0 +1,576 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant, Sleep};
+
+use crate::listener::Peer;
+use crate::{HTTPRequest, HTTPResponse, HTTPStatusCode};
+
+/// Headers that only describe a single hop and so are never forwarded in
+/// either direction, plus the framing ones the proxy sets itself.
+const HOP_BY_HOP: [&str; 10] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "host",
+];
+
+/// The upstream of `--proxy-pass http://host:port/prefix`.
+pub struct Upstream {
+    host: String,
+    port: u16,
+    /// Prepended to every forwarded path; empty or without a trailing `/`.
+    prefix: String,
+}
+
+impl FromStr for Upstream {
+    type Err = ();
+
+    fn from_str(url: &str) -> Result<Upstream, Self::Err> {
+        let rest = url.strip_prefix("http://").ok_or(())?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| ())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+            prefix: prefix.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+/// Relays `request` to the upstream, tagged with its `request_id`, and the
+/// answer back, streaming the body as it arrives. Any failure
+/// to reach the upstream or make sense of its response is a 502. The head
+/// must arrive within `timeout`, and then the body may not stall for
+/// longer than that between reads.
+pub async fn forward(
+    request: &HTTPRequest,
+    peer: Peer,
+    request_id: &str,
+    upstream: &Upstream,
+    timeout: Duration,
+) -> HTTPResponse {
+    let exchange = exchange(request, peer, request_id, upstream, timeout);
+    match time::timeout(timeout, exchange).await {
+        Ok(Some(response)) => response,
+        _ => HTTPResponse::new(HTTPStatusCode::BadGateway),
+    }
+}
+
+async fn exchange(
+    request: &HTTPRequest,
+    peer: Peer,
+    request_id: &str,
+    upstream: &Upstream,
+    timeout: Duration,
+) -> Option<HTTPResponse> {
+    let stream = TcpStream::connect((upstream.host.as_str(), upstream.port))
+        .await
+        .ok()?;
+    let mut stream = BufReader::new(stream);
+
+    let mut head = format!(
+        "{} {}{} HTTP/1.1\r\nHost: {}:{}\r\n",
+        request.method, upstream.prefix, request.target, upstream.host, upstream.port
+    );
+    for (name, value) in &request.header_lines {
+        let replaced = name == "x-forwarded-for" || name == "x-request-id";
+        if !HOP_BY_HOP.contains(&name.as_str()) && !replaced {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    // A Unix-socket peer has no address to add to the chain.
+    let forwarded_for = match (request.headers.get("x-forwarded-for"), peer.ip()) {
+        (Some(earlier), Some(ip)) => Some(format!("{}, {}", earlier, ip)),
+        (Some(earlier), None) => Some(earlier.clone()),
+        (None, ip) => ip.map(|ip| ip.to_string()),
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    head.push_str(&format!("X-Request-Id: {}\r\n", request_id));
+    // The body has already been read and de-chunked, so it is always sent
+    // with a plain length. Closing lets an unframed response end at EOF.
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        request.body.len()
+    ));
+    stream.write_all(head.as_bytes()).await.ok()?;
+    stream.write_all(&request.body).await.ok()?;
+    stream.flush().await.ok()?;
+
+    // Interim 1xx responses, such as 103 Early Hints, are skipped; only the
+    // final status is relayed, with its own reason phrase.
+    let mut response = loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let mut status = line.trim_end().splitn(3, ' ');
+        status
+            .next()
+            .filter(|version| version.starts_with("HTTP/"))?;
+        let code: u16 = status.next()?.parse().ok()?;
+        let message = status.next().unwrap_or_default();
+        if !(100..=199).contains(&code) {
+            break HTTPResponse::relayed(code, message)?;
+        }
+        loop {
+            let mut line = String::new();
+            match stream.read_line(&mut line).await.ok()? {
+                0 => return None,
+                _ if line == "\r\n" => break,
+                _ => {}
+            }
+        }
+    };
+    let mut length = None;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        if line == "\r\n" {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse::<u64>().ok()?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().contains("chunked");
+        } else if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+            response = response.with_header(name, value);
+        }
+    }
+
+    if matches!(response.status(), 204 | 304) {
+        return Some(response);
+    }
+    let stream = IdleTimeout::new(stream, timeout);
+    let response = match length {
+        Some(length) if !chunked => response.with_stream(Box::new(stream), length),
+        // A chunked body is decoded as it arrives and chunked afresh for
+        // the client; one that runs to EOF is chunked as it arrives.
+        _ if chunked => response.with_chunked(Box::new(Dechunker::new(stream))),
+        _ => response.with_chunked(Box::new(stream)),
+    };
+    Some(response)
+}
+
+/// Fails a read with `TimedOut` once the inner reader has produced nothing
+/// for `timeout`, so a stalled upstream can't hold the client's connection
+/// open forever.
+struct IdleTimeout<R> {
+    inner: R,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<R: AsyncBufRead + Unpin> IdleTimeout<R> {
+    fn new(inner: R, timeout: Duration) -> IdleTimeout<R> {
+        IdleTimeout {
+            inner,
+            timeout,
+            deadline: Box::pin(time::sleep(timeout)),
+        }
+    }
+
+    /// Passes on a ready read, pushing the deadline back, or else turns a
+    /// pending one into an error once the deadline has passed.
+    fn poll_timed<T>(
+        deadline: &mut Pin<Box<Sleep>>,
+        timeout: Duration,
+        cx: &mut Context<'_>,
+        read: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match read {
+            Poll::Ready(result) => {
+                deadline.as_mut().reset(Instant::now() + timeout);
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                ready!(deadline.as_mut().poll(cx));
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for IdleTimeout<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let read = Pin::new(&mut this.inner).poll_read(cx, buf);
+        Self::poll_timed(&mut this.deadline, this.timeout, cx, read)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for IdleTimeout<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let read = Pin::new(&mut this.inner).poll_fill_buf(cx);
+        Self::poll_timed(&mut this.deadline, this.timeout, cx, read)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt);
+    }
+}
+
+/// How long a chunk-size line, or a trailer line, from the upstream may be.
+const MAX_CHUNK_LINE: usize = 8 * 1024;
+
+/// Reads the data out of a `Transfer-Encoding: chunked` body, a chunk at a
+/// time, ending at the zero-sized chunk and discarding any trailers. Bad
+/// framing is an `InvalidData` error, which cuts the relayed body short
+/// rather than letting it look complete.
+struct Dechunker<R> {
+    inner: R,
+    state: DechunkState,
+    line: Vec<u8>,
+}
+
+enum DechunkState {
+    /// Expecting a chunk-size line.
+    Size,
+    /// This many bytes of chunk data are still to come.
+    Data(u64),
+    /// Expecting the CRLF after a chunk's data.
+    DataEnd,
+    /// Expecting trailer lines up to a blank one.
+    Trailers,
+    Done,
+}
+
+impl<R: AsyncBufRead + Unpin> Dechunker<R> {
+    fn new(inner: R) -> Dechunker<R> {
+        Dechunker {
+            inner,
+            state: DechunkState::Size,
+            line: Vec::new(),
+        }
+    }
+
+    /// Collects the next line, CRLF and all, into `self.line`.
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let available = ready!(Pin::new(&mut self.inner).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let (taken, done) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (available.len(), false),
+            };
+            self.line.extend_from_slice(&available[..taken]);
+            Pin::new(&mut self.inner).consume(taken);
+            if self.line.len() > MAX_CHUNK_LINE {
+                return Poll::Ready(Err(invalid("chunk line too long")));
+            }
+            if done {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for Dechunker<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                DechunkState::Done => return Poll::Ready(Ok(())),
+                DechunkState::Data(remaining) => {
+                    if buf.remaining() == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let available = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+                    if available.is_empty() {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    let len = (available.len() as u64)
+                        .min(remaining)
+                        .min(buf.remaining() as u64) as usize;
+                    buf.put_slice(&available[..len]);
+                    Pin::new(&mut this.inner).consume(len);
+                    this.state = match remaining - len as u64 {
+                        0 => DechunkState::DataEnd,
+                        remaining => DechunkState::Data(remaining),
+                    };
+                    return Poll::Ready(Ok(()));
+                }
+                DechunkState::Size | DechunkState::DataEnd | DechunkState::Trailers => {
+                    ready!(this.poll_line(cx))?;
+                    let line = std::mem::take(&mut this.line);
+                    this.state = match this.state {
+                        DechunkState::Size => {
+                            let line = std::str::from_utf8(&line)
+                                .map_err(|_| invalid("bad chunk size"))?;
+                            // Chunk extensions after `;` carry nothing we use.
+                            let size = line.split(';').next().unwrap_or_default().trim();
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => DechunkState::Trailers,
+                                Ok(size) => DechunkState::Data(size),
+                                Err(_) => return Poll::Ready(Err(invalid("bad chunk size"))),
+                            }
+                        }
+                        DechunkState::DataEnd if line == b"\r\n" => DechunkState::Size,
+                        DechunkState::DataEnd => {
+                            return Poll::Ready(Err(invalid("chunk data overran its size")))
+                        }
+                        _ if line == b"\r\n" => DechunkState::Done,
+                        _ => DechunkState::Trailers,
+                    };
+                }
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::tests::{config_for, exchange, peer, response, scratch_dir};
+    use crate::{handle_connection, handlers};
+
+    /// An upstream that answers one request with `reply`, handing back the
+    /// request it received.
+    async fn stub(reply: &'static [u8]) -> (Upstream, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = format!("http://{}/api", address).parse().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 4096];
+            // The proxy always frames its request with Content-Length.
+            loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&received);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |length| length.parse().unwrap());
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            socket.write_all(reply).await.unwrap();
+            String::from_utf8(received).unwrap()
+        });
+        (upstream, handle)
+    }
+
+    async fn proxy(upstream: Upstream, request: &str) -> Vec<u8> {
+        let config = Config {
+            proxy_pass: Some(upstream),
+            ..config_for(&scratch_dir("proxy"))
+        };
+        exchange(&config, request.as_bytes()).await
+    }
+
+    /// Like `proxy`, but for relays expected to fail partway: hands back
+    /// how the connection ended along with what the client received.
+    async fn proxy_failing(upstream: Upstream, timeout: Duration) -> (io::Error, Vec<u8>) {
+        let config = Config {
+            proxy_pass: Some(upstream),
+            read_timeout: timeout,
+            ..config_for(&scratch_dir("proxy"))
+        };
+        let router = handlers::router();
+        let (mut client, server_end) = tokio::io::duplex(1 << 16);
+        let server = async {
+            let mut reader = BufReader::new(server_end);
+            handle_connection(&mut reader, peer(), &config, &router, &Metrics::new(), None).await
+        };
+        let client = async move {
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let (served, output) = tokio::join!(server, client);
+        (served.unwrap_err(), output)
+    }
+
+    #[tokio::test]
+    async fn relays_the_request_and_the_upstream_response() {
+        let (upstream, received) =
+            stub(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Upstream: yes\r\n\r\nhello").await;
+        let response = response(&proxy(
+            upstream,
+            "POST /items?id=1 HTTP/1.1\r\nHost: test\r\nX-Custom: kept\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .await);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("X-Upstream"), Some("yes"));
+        assert_eq!(response.text(), "hello");
+
+        let received = received.await.unwrap();
+        assert!(
+            received.starts_with("POST /api/items?id=1 HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+        assert!(received.contains("\r\nx-custom: kept\r\n"));
+        assert!(received.contains("\r\nX-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(received.ends_with("\r\n\r\nbody"));
+    }
+
+    #[tokio::test]
+    async fn statuses_without_a_variant_are_relayed_as_sent() {
+        for (reply, status, reason) in [
+            (
+                &b"HTTP/1.1 307 Temporary Redirect\r\nLocation: /elsewhere\r\nContent-Length: 0\r\n\r\n"[..],
+                307,
+                "Temporary Redirect",
+            ),
+            (
+                b"HTTP/1.1 422 Unprocessable Content\r\nContent-Length: 2\r\n\r\nno",
+                422,
+                "Unprocessable Content",
+            ),
+        ] {
+            let (upstream, _) = stub(reply).await;
+            let output = proxy(upstream, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+            let status_line = format!("HTTP/1.1 {} {}\r\n", status, reason);
+            assert!(output.starts_with(status_line.as_bytes()));
+            assert_eq!(response(&output).status, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_upstream_bodies_are_streamed_through() {
+        let (upstream, _) = stub(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n7;ext\r\n, world\r\n0\r\nX-Trailer: dropped\r\n\r\n",
+        )
+        .await;
+        let response = response(&proxy(upstream, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(response.text(), "hello, world");
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let (upstream, _) = stub(
+            b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .await;
+        let response = response(&proxy(upstream, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "ok");
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_upstream_is_a_bad_gateway() {
+        // Nothing listens on the port once its listener has been dropped.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = format!("http://{}", address).parse().unwrap();
+        let response = response(&proxy(upstream, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await);
+        assert_eq!(response.status, 502);
+    }
+
+    #[tokio::test]
+    async fn an_upstream_body_cut_short_closes_the_connection() {
+        let (upstream, _) = stub(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort").await;
+        let (error, output) = proxy_failing(upstream, Duration::from_secs(5)).await;
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(output.ends_with(b"\r\n\r\nshort"));
+    }
+
+    #[tokio::test]
+    async fn a_stalled_upstream_body_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let _upstream = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhel")
+                .await
+                .unwrap();
+            // Then nothing more, without ever closing.
+            std::future::pending::<()>().await;
+        });
+        let relayed = proxy_failing(upstream, Duration::from_millis(100));
+        let (error, output) = time::timeout(Duration::from_secs(2), relayed)
+            .await
+            .expect("stalled body never timed out");
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(output.ends_with(b"\r\n\r\nhel"));
+    }
+
+    #[tokio::test]
+    async fn monitoring_is_answered_locally_during_maintenance() {
+        let (upstream, _) = stub(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nupstream").await;
+        let config = Config {
+            proxy_pass: Some(upstream),
+            maintenance: true,
+            ..config_for(&scratch_dir("proxy-maintenance"))
+        };
+        let output = exchange(&config, b"GET /health HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        let health = response(&output);
+        assert_eq!(health.status, 200);
+        assert_eq!(health.text(), r#"{"status":"ok"}"#);
+        let output = exchange(&config, b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert!(response(&output).text().contains("http_requests_total"));
+
+        let output = exchange(&config, b"GET /items HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert_eq!(response(&output).status, 503);
+    }
+}
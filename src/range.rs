@@ -0,0 +1,149 @@
+/// An inclusive span of bytes within a file.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// None of the requested ranges overlap the file.
+pub struct Unsatisfiable;
+
+/// The most ranges, once overlapping ones are merged, answered as a
+/// multipart body; a header asking for more is ignored.
+pub const MAX_RANGES: usize = 16;
+
+/// Parses a `Range` header such as `bytes=0-99,200-`, `bytes=500-` or
+/// `bytes=-500` against a file of `size` bytes. Returns `None` when the
+/// header is malformed or uses a unit other than bytes, in which case it is
+/// to be ignored and the whole file served. Ranges starting past the end of
+/// the file are dropped, and ends past it are clamped. Overlapping and
+/// adjacent ranges are merged and returned in order, so the parts never
+/// add up to more than the file itself.
+pub fn parse(header: &str, size: u64) -> Option<Result<Vec<ByteRange>, Unsatisfiable>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            // A suffix range: the last `end` bytes.
+            ("", suffix) => {
+                let suffix = suffix.parse::<u64>().ok()?;
+                if suffix == 0 || size == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: size.saturating_sub(suffix),
+                    end: size - 1,
+                }
+            }
+            (start, end) => {
+                let start = start.parse::<u64>().ok()?;
+                let end = match end {
+                    "" => u64::MAX,
+                    end => end.parse::<u64>().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                if start >= size {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.min(size - 1),
+                }
+            }
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() {
+        return Some(Err(Unsatisfiable));
+    }
+    let ranges = coalesce(ranges);
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    Some(Ok(ranges))
+}
+
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(header: &str, size: u64) -> Vec<(u64, u64)> {
+        match parse(header, size) {
+            Some(Ok(ranges)) => ranges
+                .iter()
+                .map(|range| (range.start, range.end))
+                .collect(),
+            _ => panic!("{} should be satisfiable", header),
+        }
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged_in_order() {
+        assert_eq!(spans("bytes=50-59,0-9,5-19", 100), [(0, 19), (50, 59)]);
+        assert_eq!(spans("bytes=0-9,10-19", 100), [(0, 19)]);
+        assert_eq!(spans("bytes=-10,0-", 100), [(0, 99)]);
+    }
+
+    #[test]
+    fn repeats_of_one_range_collapse_to_it() {
+        let header = format!("bytes={}", vec!["0-0"; 1900].join(","));
+        assert_eq!(spans(&header, 100), [(0, 0)]);
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let ranges: Vec<String> = (0..=MAX_RANGES)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .collect();
+        let header = format!("bytes={}", ranges.join(","));
+        assert!(parse(&header, 1000).is_none());
+    }
+
+    #[test]
+    fn single_ranges_are_clamped_to_the_file() {
+        assert_eq!(spans("bytes=0-1023", 2000), [(0, 1023)]);
+        assert_eq!(spans("bytes=500-", 2000), [(500, 1999)]);
+        assert_eq!(spans("bytes=-500", 2000), [(1500, 1999)]);
+        assert_eq!(spans("bytes=1990-5000", 2000), [(1990, 1999)]);
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert!(matches!(
+            parse("bytes=2000-", 2000),
+            Some(Err(Unsatisfiable))
+        ));
+        assert!(matches!(parse("bytes=-0", 2000), Some(Err(Unsatisfiable))));
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for header in ["items=0-1", "bytes=", "bytes=5-1", "bytes=a-b", "bytes=-"] {
+            assert!(parse(header, 2000).is_none(), "{}", header);
+        }
+    }
+}
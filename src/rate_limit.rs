@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets are only pruned once there are this many, to keep the common
+/// path cheap.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client IP for `--rate-limit`: each request takes a
+/// token, buckets refill at `rate` tokens a second and hold at most one
+/// second's worth, so short bursts up to the rate are allowed.
+pub struct RateLimiter {
+    rate: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    /// Takes a token for `ip`, or says how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = self.capacity();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // A bucket that has refilled completely is the same as none.
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * self.rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_past_the_rate_is_refused() {
+        let limiter = RateLimiter::new(3.0);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..3 {
+            assert!(limiter.check(client).is_ok());
+        }
+        let wait = limiter.check(client).unwrap_err();
+        assert!(
+            wait > Duration::ZERO && wait <= Duration::from_millis(334),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn clients_have_their_own_buckets() {
+        let limiter = RateLimiter::new(1.0);
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 1])).is_ok());
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 1])).is_err());
+        assert!(limiter.check(IpAddr::from([192, 0, 2, 2])).is_ok());
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(50.0);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        while limiter.check(client).is_ok() {}
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(limiter.check(client).is_ok());
+    }
+}
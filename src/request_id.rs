@@ -0,0 +1,26 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh ID for a request that didn't bring its own `X-Request-Id`: a
+/// randomly keyed hash, to keep IDs from separate runs apart, followed by
+/// a counter, to keep IDs within one run unique.
+pub fn generate() -> String {
+    let sequence = NEXT.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(sequence);
+    format!("{:016x}-{:x}", hasher.finish(), sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..1000).map(|_| generate()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+}
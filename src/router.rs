@@ -0,0 +1,171 @@
+use crate::config::Config;
+use crate::{HTTPMethod, HTTPRequest, HTTPResponse, HTTPStatusCode};
+
+pub type Handler = fn(&HTTPRequest, &Config) -> HTTPResponse;
+
+struct Route {
+    method: HTTPMethod,
+    pattern: String,
+    handler: Handler,
+}
+
+impl Route {
+    /// A pattern ending in `*` matches any path with that prefix, so
+    /// `/echo/*` covers `/echo/` and everything below it; any other pattern
+    /// must match the path exactly.
+    fn matches_path(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.pattern,
+        }
+    }
+
+    /// Routes registered for GET also answer HEAD requests.
+    fn matches_method(&self, method: HTTPMethod) -> bool {
+        self.method == method || (self.method == HTTPMethod::GET && method == HTTPMethod::HEAD)
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn register(&mut self, method: HTTPMethod, pattern: &str, handler: Handler) {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            handler,
+        });
+    }
+
+    /// Dispatches to the first route registered for the request's method and
+    /// path. A path that is routed only for other methods gets a 405 listing
+    /// them in `Allow`; an unknown path gets a 404. Unless a route handles
+    /// it, OPTIONS is answered with a 204 listing the path's methods, or
+    /// every routed method for `OPTIONS *`.
+    pub fn route(&self, request: &HTTPRequest, config: &Config) -> HTTPResponse {
+        let route = self.routes.iter().find(|route| {
+            route.matches_method(request.method) && route.matches_path(&request.path)
+        });
+        if let Some(route) = route {
+            return (route.handler)(request, config);
+        }
+
+        let allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            return HTTPResponse::new(HTTPStatusCode::NotFound);
+        }
+        let allowed: Vec<String> = allowed.iter().map(|method| method.to_string()).collect();
+        let response = if request.method == HTTPMethod::OPTIONS {
+            HTTPResponse::new(HTTPStatusCode::NoContent)
+        } else {
+            HTTPResponse::new(HTTPStatusCode::MethodNotAllowed)
+        };
+        response.with_header("Allow", allowed.join(", "))
+    }
+
+    /// Every method some route accepts for `path`, in registration order,
+    /// followed by OPTIONS; `*` stands for every route.
+    pub fn allowed_methods(&self, path: &str) -> Vec<HTTPMethod> {
+        let mut methods = Vec::new();
+        let routes = self
+            .routes
+            .iter()
+            .filter(|route| path == "*" || route.matches_path(path));
+        for route in routes {
+            if !methods.contains(&route.method) {
+                methods.push(route.method);
+            }
+            if route.method == HTTPMethod::GET && !methods.contains(&HTTPMethod::HEAD) {
+                methods.push(HTTPMethod::HEAD);
+            }
+        }
+        if !methods.is_empty() && !methods.contains(&HTTPMethod::OPTIONS) {
+            methods.push(HTTPMethod::OPTIONS);
+        }
+        methods
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::parse;
+
+    fn exact(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+        HTTPResponse::ok().with_header("X-Handler", "exact")
+    }
+
+    fn wildcard(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+        HTTPResponse::ok().with_header("X-Handler", "wildcard")
+    }
+
+    fn upload(_request: &HTTPRequest, _config: &Config) -> HTTPResponse {
+        HTTPResponse::new(HTTPStatusCode::Created)
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.register(HTTPMethod::GET, "/ping", exact);
+        router.register(HTTPMethod::GET, "/things/*", wildcard);
+        router.register(HTTPMethod::POST, "/things/*", upload);
+        router
+    }
+
+    async fn route(head: &str) -> HTTPResponse {
+        let request = parse(format!("{}\r\nHost: test\r\n\r\n", head).as_bytes()).await;
+        router().route(&request, &Config::default())
+    }
+
+    #[tokio::test]
+    async fn dispatches_exact_and_wildcard_routes() {
+        let response = route("GET /ping HTTP/1.1").await;
+        assert_eq!(response.header("X-Handler"), Some("exact"));
+        let response = route("GET /things/a/b HTTP/1.1").await;
+        assert_eq!(response.header("X-Handler"), Some("wildcard"));
+        let response = route("HEAD /things/ HTTP/1.1").await;
+        assert_eq!(response.header("X-Handler"), Some("wildcard"));
+        assert_eq!(
+            route("POST /things/a HTTP/1.1\r\nContent-Length: 0")
+                .await
+                .status(),
+            201
+        );
+    }
+
+    #[tokio::test]
+    async fn unrouted_paths_are_not_found() {
+        assert_eq!(route("GET /ping/more HTTP/1.1").await.status(), 404);
+        assert_eq!(route("GET /things HTTP/1.1").await.status(), 404);
+        assert_eq!(route("GET /nowhere HTTP/1.1").await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn other_methods_on_a_routed_path_are_not_allowed() {
+        let response = route("DELETE /things/a HTTP/1.1").await;
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        let response = route("PUT /ping HTTP/1.1\r\nContent-Length: 0").await;
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_methods_of_a_route_or_of_all_routes() {
+        let response = route("OPTIONS /things/a HTTP/1.1").await;
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        let response = route("OPTIONS /ping HTTP/1.1").await;
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        let response = route("OPTIONS * HTTP/1.1").await;
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        assert_eq!(route("OPTIONS /nowhere HTTP/1.1").await.status(), 404);
+    }
+}
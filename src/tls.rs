@@ -0,0 +1,112 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Builds an acceptor from a PEM certificate chain and a PEM private key
+/// (PKCS#1, PKCS#8 or SEC1).
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("could not read {}: {}", cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("could not read {}: {}", key.display(), e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("invalid TLS configuration: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use tokio::time;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use crate::config::Config;
+    use crate::handlers;
+    use crate::metrics::Metrics;
+    use crate::tests::{config_for, peer, response, scratch_dir};
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// A client trusting only the fixtures' test CA.
+    fn connector() -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(fixture("ca.pem")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn serves_a_get_over_tls() {
+        let acceptor = acceptor(&fixture("cert.pem"), &fixture("key.pem")).unwrap();
+        let dir = scratch_dir("tls-get");
+        std::fs::write(dir.join("index.html"), "<h1>secure</h1>").unwrap();
+        let config = config_for(&dir);
+        let router = handlers::router();
+        let metrics = Metrics::new();
+        let (client, server) = io::duplex(64 * 1024);
+
+        let server = crate::serve_tls(&acceptor, server, peer(), &config, &router, &metrics, None);
+        let client = async {
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector().connect(name, client).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut output = Vec::new();
+            // Without the server's close_notify, rustls reports the end of
+            // the stream as an unexpected EOF.
+            stream.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let ((), output) = tokio::join!(server, client);
+        let response = response(&output);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "<h1>secure</h1>");
+    }
+
+    #[tokio::test]
+    async fn a_client_that_never_says_hello_is_dropped() {
+        let acceptor = acceptor(&fixture("cert.pem"), &fixture("key.pem")).unwrap();
+        let config = Config {
+            read_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let router = handlers::router();
+        let metrics = Metrics::new();
+        let (_client, server) = io::duplex(1024);
+        let served = crate::serve_tls(&acceptor, server, peer(), &config, &router, &metrics, None);
+        time::timeout(Duration::from_secs(5), served)
+            .await
+            .expect("the handshake should have timed out");
+    }
+}
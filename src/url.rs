@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+/// Decodes `%XX` escapes. Malformed escapes such as `%ZZ` or a trailing `%`
+/// are kept literally rather than rejected.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escape = input.get(i + 1..i + 3);
+            if let Some(byte) = escape.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes `path` and resolves it the way the files endpoints do, so
+/// `/a//b/./c/../d` and `/a/%62/d` both give `/a/b/d`. `..` never climbs
+/// above the root.
+pub fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode(path);
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Escapes everything but unreserved characters (RFC 3986), so the result
+/// is safe to use as a single path segment.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decodes a key or value of a query string, where `+` stands for a space.
+pub fn decode_component(input: &str) -> String {
+    percent_decode(&input.replace('+', " "))
+}
+
+/// Parses `a=1&b=2` into a map. A parameter without `=` maps to an empty
+/// value, and later duplicates override earlier ones.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+/// Decodes an `application/x-www-form-urlencoded` body, which uses the same
+/// `key=value&...` syntax as a query string.
+pub fn parse_form_urlencoded(body: &[u8]) -> HashMap<String, String> {
+    parse_query(&String::from_utf8_lossy(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_resolves_dots_and_empty_segments() {
+        assert_eq!(normalize_path("/a//b/./c/../d"), "/a/b/d");
+        assert_eq!(normalize_path("/a/%62/d"), "/a/b/d");
+        assert_eq!(normalize_path("/../../etc"), "/etc");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/a/"), "/a");
+    }
+
+    #[test]
+    fn parse_query_decodes_every_parameter() {
+        let query = parse_query("times=3&greeting=hello+big%20world&a%26b=c%3Dd");
+        assert_eq!(query.len(), 3);
+        assert_eq!(query["times"], "3");
+        assert_eq!(query["greeting"], "hello big world");
+        assert_eq!(query["a&b"], "c=d");
+    }
+
+    #[test]
+    fn parse_query_keeps_parameters_without_a_value() {
+        let query = parse_query("flag&empty=&&x=1");
+        assert_eq!(query.len(), 3);
+        assert_eq!(query["flag"], "");
+        assert_eq!(query["empty"], "");
+        assert_eq!(query["x"], "1");
+    }
+
+    #[test]
+    fn parse_query_of_nothing_is_empty() {
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("hello%20world"), "hello world");
+        assert_eq!(percent_decode("a%2Fb%2fc"), "a/b/c");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%ZZ"), "100%ZZ");
+        assert_eq!(percent_decode("trailing%"), "trailing%");
+        assert_eq!(percent_decode("short%2"), "short%2");
+    }
+
+    #[test]
+    fn parse_form_urlencoded_decodes_pairs() {
+        let form = parse_form_urlencoded(b"name=alice&age=30&company=Smith+%26+Sons");
+        assert_eq!(form.len(), 3);
+        assert_eq!(form["name"], "alice");
+        assert_eq!(form["age"], "30");
+        assert_eq!(form["company"], "Smith & Sons");
+    }
+}
@@ -0,0 +1,51 @@
+use crate::base64;
+use crate::sha1;
+use crate::{HTTPMethod, HTTPRequest, HTTPResponse, HTTPStatusCode};
+
+/// Appended to the client's key before hashing, as fixed by RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Whether the request asks to switch the connection to WebSocket.
+pub fn is_upgrade(request: &HTTPRequest) -> bool {
+    request.method == HTTPMethod::GET
+        && request.has_connection_option("upgrade")
+        && request
+            .headers
+            .get("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+/// The `Sec-WebSocket-Accept` value proving the server read `key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Answers an upgrade request with 101, or 400 when its key isn't the
+/// base64 encoding of 16 bytes that RFC 6455 requires.
+pub fn handshake(request: &HTTPRequest) -> HTTPResponse {
+    let key = request
+        .headers
+        .get("sec-websocket-key")
+        .filter(|key| base64::decode(key).is_some_and(|nonce| nonce.len() == 16));
+    match key {
+        Some(key) => HTTPResponse::new(HTTPStatusCode::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept_key(key)),
+        None => HTTPResponse::new(HTTPStatusCode::BadRequest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        // RFC 6455, section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}